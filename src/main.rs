@@ -7,8 +7,10 @@
 //
 // Crates
 //
+use bevy::audio::Volume;
+use bevy::ecs::system::SystemParam;
 use bevy::prelude::*;
-use bevy::window::{WindowMode, WindowResolution};
+use bevy::window::{PresentMode, PrimaryWindow, WindowMode, WindowResolution};
 use rand::prelude::*;
 
 //
@@ -29,6 +31,112 @@ struct RelativePosition {
     rot_x: i32,
     rot_y: i32,
 }
+// 回転の中心（ピースの原点からのずれを半マス単位で持つ）
+// I と四角はマスの角を中心に回す
+#[derive(Component, Clone, Copy)]
+struct RotationCenter {
+    x2: i32,
+    y2: i32,
+}
+// ブロック本来の色（表示上の色合いを変える時の基準）
+#[derive(Component)]
+struct BlockColor(Color);
+// 同じピースを構成するブロックに共通の番号
+#[derive(Component, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+struct PieceId(u32);
+// 時間が経つと見えなくなるブロック
+#[derive(Component)]
+struct FadeOut {
+    delay: Timer,
+    fade: Timer,
+}
+// 生成されたばかりのピースを小さい状態から拡大する演出（見た目だけで、位置と当たり判定は変えない）
+#[derive(Component)]
+struct SpawnAnimation(Timer);
+// ライン消去で下がったブロックを元の高さから滑らせる演出（Position は下がった先に更新済み）
+#[derive(Component)]
+struct CollapseAnimation {
+    rows: i32,
+    timer: Timer,
+}
+
+//
+// Component: UI
+//
+// コンボの残り時間を表すバー
+#[derive(Component)]
+struct ComboBar;
+// ポーズ中の表示
+#[derive(Component)]
+struct PauseText;
+// 設定メニューの表示
+#[derive(Component)]
+struct OptionsText;
+// ゲームオーバー後の結果表示
+#[derive(Component)]
+struct ResultsText;
+// 現在の落下速度の表示
+#[derive(Component)]
+struct GravityText;
+// スローモーション中の表示
+#[derive(Component)]
+struct SlowMotionText;
+// プレイ時間の表示
+#[derive(Component)]
+struct RunClockText;
+// 盤面の枠
+#[derive(Component)]
+struct BoardBorder;
+// 盤面の右に置く積み上がりの高さのゲージ
+#[derive(Component)]
+struct HeightMeter;
+// 次にせり上がってくるおじゃまブロックの行数を示す盤面の左端の列
+#[derive(Component)]
+struct GarbageWarning;
+// 次のおじゃまブロックがせり上がるまでの残り時間の表示
+#[derive(Component)]
+struct GarbageWarningText;
+// 得点の表示（盤面の左の余白）
+#[derive(Component)]
+struct ScoreText;
+// 次のピースの表示のブロック（盤面の右の余白）
+#[derive(Component, Clone, Copy)]
+struct PreviewBlock;
+// ホールドしているピースの表示のブロック（盤面の左の余白）
+#[derive(Component, Clone, Copy)]
+struct HoldPreviewBlock;
+// 盤面の背景のマス
+#[derive(Component)]
+struct BackgroundCell;
+// 操作中のピースが落ちる位置に半透明で表示するブロック（盤面には書き込まない）
+// 操作しない間に薄くしていく時の元の不透明度を持つ
+#[derive(Component)]
+struct GhostBlock(f32);
+// おすすめの置き場所を示すマス（操作中のピースのブロックの数だけ用意する）
+#[derive(Component)]
+struct HintCell;
+// 操作中のピースが落ちる位置から床までの列の影（x: 列）
+#[derive(Component)]
+struct ColumnShadow(i32);
+// 盤面を狭めるために埋めた壁のマス
+#[derive(Component)]
+struct Wall;
+// 文字の拡大率を掛ける前の各セクションの文字サイズ
+#[derive(Component)]
+struct BaseFontSize(Vec<f32>);
+
+//
+// Component: Audio
+//
+// BGM として再生中の音声
+#[derive(Component)]
+struct Music;
+// BGM のフェードイン・フェードアウト
+#[derive(Component)]
+struct MusicFade {
+    timer: Timer,
+    fade_in: bool,
+}
 
 //
 // Resource: Block
@@ -37,8 +145,87 @@ struct RelativePosition {
 struct Materials {
     colors: Vec<Color>,
 }
+// ピースの形（ブロックの相対座標）と回転の中心
+#[derive(Clone)]
+struct BlockPattern {
+    cells: Vec<(i32, i32)>,
+    center: RotationCenter,
+}
+#[derive(Resource)]
+struct BlockPatterns(Vec<BlockPattern>);
+// 次に生成するピースの形と色（まだ決めていなければ None）
+#[derive(Resource, Default, Clone)]
+struct NextPiece(Option<(BlockPattern, Color)>);
+// ホールドしているピースの形（出てきた時の向き）と色
+#[derive(Resource, Default, Clone)]
+struct Hold {
+    pattern: Option<Vec<(i32, i32)>>,
+    color: Option<Color>,
+    // 今のピースで一度ホールドしたか（次のピースが出るまでは使えない）
+    used_this_turn: bool,
+    // ホールドで次に出すピース（入れ替えた前のホールド。空だった時は None で、次のピースを出す）
+    swap_in: Option<(Vec<(i32, i32)>, Color)>,
+    // ホールドの操作でピースを出すのを待っているか
+    swap_requested: bool,
+}
+// 次に生成するピースの番号
+#[derive(Resource, Default)]
+struct PieceCounter(u32);
+
+//
+// Resource: Practice
+//
+// 固定されたピース（元に戻す時に使う）
+struct LockedPiece {
+    piece_id: PieceId,
+    cells: Vec<(i32, i32)>,
+}
+// 固定されたピースの履歴（新しいものが後ろ）
+#[derive(Resource, Default)]
+struct LockHistory(Vec<LockedPiece>);
+// チェックポイントに保存したブロック１つ分
+struct SavedBlock {
+    x: i32,
+    y: i32,
+    color: Color,
+    piece_id: Option<PieceId>,
+    relative: Option<(i32, i32)>,
+    center: Option<RotationCenter>,
+    free: bool,
+}
+// 練習モードで保存した盤面と操作中のピースと乱数の状態
+struct Checkpoint {
+    board: Vec<Vec<bool>>,
+    blocks: Vec<SavedBlock>,
+    rng: StdRng,
+    piece_counter: u32,
+    combo: u32,
+    score: u32,
+    piece_bag: PieceBag,
+    next_piece: NextPiece,
+    hold: Hold,
+}
+#[derive(Resource, Default)]
+struct PracticeCheckpoint(Option<Checkpoint>);
+
+//
+// Resource: Random
+//
+// ピースと色の決定に使う乱数
+#[derive(Resource)]
+struct GameRng(StdRng);
+// まだ出していないピースの形の番号（すべての形を１つずつ混ぜて入れ、空になったら詰め直す）
+#[derive(Resource, Default, Clone)]
+struct PieceBag {
+    queue: std::collections::VecDeque<usize>,
+}
+// 現在のゲームの乱数のシード
 #[derive(Resource)]
-struct BlockPatterns(Vec<Vec<(i32, i32)>>);
+struct RunSeed(u64);
+// 補助機能（元に戻す、スローモーション、盤面の編集、invisible の表示）を使ったゲームか
+// ハイスコアを記録する時は補助なしのゲームだけを対象にすること
+#[derive(Resource, Default)]
+struct RunAssisted(bool);
 
 //
 // Resource: Timer
@@ -48,6 +235,111 @@ struct GameTimer(Timer);
 // 入力を受け付けるタイマー
 #[derive(Resource)]
 struct InputTimer(Timer);
+// 回転してから次に回転できるまでの待ち時間
+#[derive(Resource)]
+struct RotateCooldown(Timer);
+// プレイ中に R キーでやり直すまでに押し続ける時間（confirm_restart の時）
+#[derive(Resource)]
+struct RestartHold(Timer);
+// ゴーストを最後に操作してから表示しておく残り時間（ghost_fade_ms の時）
+#[derive(Resource)]
+struct GhostFade(Timer);
+// コンボを継続できる残り時間
+#[derive(Resource)]
+struct ComboTimer(Timer);
+
+// 画面に表示する文字列（文字列の ID → 表示する言語の文字列）と、その言語で使うフォント
+#[derive(Resource)]
+struct UiStrings {
+    strings: std::collections::HashMap<String, String>,
+    font: Option<String>,
+}
+
+impl UiStrings {
+    /**
+     * 文字列の ID に対応する表示用の文字列（未知の ID はそのまま返す）
+     */
+    fn get<'a>(&'a self, id: &'a str) -> &'a str {
+        self.strings.get(id).map_or(id, |text| text.as_str())
+    }
+}
+
+// 最初のピースからのプレイ時間（ポーズ中とゲームオーバー後は止まる）
+#[derive(Resource, Default)]
+struct RunClock(bevy::time::Stopwatch);
+
+//
+// Resource: Combo
+//
+// 連続でラインを消去した回数
+#[derive(Resource, Default)]
+struct Combo(u32);
+
+//
+// Resource: Score
+//
+// ラインを消去して得た得点
+#[derive(Resource, Default)]
+struct Score(u32);
+
+//
+// Resource: Debug
+//
+// 落下・入力のタイマーを進める速さの倍率（スローモーション用）
+#[derive(Resource)]
+struct TimeScale(f32);
+// デバッグ用に次のピースの生成を遅らせている残り時間
+#[derive(Resource, Default)]
+struct SpawnDelay(Option<Timer>);
+// ブロックを盤面の座標で色分けして表示するか（座標変換の確認用）
+#[derive(Resource, Default)]
+struct CoordinateColors(bool);
+// 直近のキー入力の記録（不具合の再現用、古いものから捨てる）
+#[derive(Resource)]
+struct InputLog {
+    frame: u64,
+    capacity: usize,
+    entries: std::collections::VecDeque<(u64, KeyCode, bool)>,
+}
+
+//
+// Resource: Survival
+//
+// おじゃまブロックがせり上がるまでの時間
+#[derive(Resource)]
+struct GarbageTimer(Timer);
+// サバイバルモードで生き残っている時間
+#[derive(Resource, Default)]
+struct SurvivalTime(std::time::Duration);
+
+//
+// Resource: Shrink
+//
+// 盤面の左右を埋めた列の数
+#[derive(Resource, Default)]
+struct PlayfieldWalls {
+    left: i32,
+    right: i32,
+}
+
+impl PlayfieldWalls {
+    /**
+     * ブロックを置ける列の数
+     */
+    fn width(&self) -> i32 {
+        X_LENGTH as i32 - self.left - self.right
+    }
+
+    /**
+     * ピースを生成する列（置ける範囲の中央）
+     */
+    fn spawn_x(&self) -> i32 {
+        (self.left + X_LENGTH as i32 - self.right) / 2
+    }
+}
+// 盤面が狭まるまでの時間
+#[derive(Resource)]
+struct ShrinkTimer(Timer);
 
 
 //
@@ -56,503 +348,6032 @@ struct InputTimer(Timer);
 #[derive(Resource)]
 struct GameBoard(Vec<Vec<bool>>);
 
+impl GameBoard {
+    /**
+     * 空の盤面（上にはみ出したブロック用に余裕を持たせる）
+     */
+    fn new() -> GameBoard {
+        GameBoard(vec![vec![false; 25]; 25])
+    }
+
+    /**
+     * 盤面より上のバッファ領域も含めた盤面の高さ
+     */
+    fn height(&self) -> i32 {
+        self.0.len() as i32
+    }
+
+    /**
+     * マスの状態を取得する（盤面の範囲外なら None）
+     */
+    fn get(&self, x: i32, y: i32) -> Option<bool> {
+        if x < 0 || x >= X_LENGTH as i32 || y < 0 {
+            return None;
+        }
+        self.0.get(y as usize).map(|row| row[x as usize])
+    }
+
+    /**
+     * マスの状態を設定する（盤面の範囲外は無視する）
+     */
+    fn set(&mut self, x: i32, y: i32, filled: bool) {
+        if x < 0 || x >= X_LENGTH as i32 || y < 0 {
+            return;
+        }
+        if let Some(row) = self.0.get_mut(y as usize) {
+            row[x as usize] = filled;
+        }
+    }
+
+    /**
+     * ブロックを置けるマスか（左右の壁と床の外は置けない、盤面より上は空）
+     */
+    fn is_vacant(&self, x: i32, y: i32) -> bool {
+        if x < 0 || x >= X_LENGTH as i32 || y < 0 {
+            return false;
+        }
+        !self.get(x, y).unwrap_or(false)
+    }
+
+    /**
+     * 積み上がっているブロックの高さ（一番上のブロックがある行 + 1）
+     */
+    fn stack_height(&self) -> i32 {
+        (0..self.height())
+            .rev()
+            .find(|y| (0..X_LENGTH as i32).any(|x| self.get(x, *y) == Some(true)))
+            .map_or(0, |y| y + 1)
+    }
+
+    /**
+     * 盤面全体を１段押し上げ、一番下に hole の列だけ空いたおじゃまブロックの行を入れる
+     * 盤面の一番上の行ははみ出して消える
+     */
+    fn insert_garbage_line(&mut self, hole: i32) {
+        let width = self.0[0].len();
+        self.0.pop();
+        self.0.insert(0, vec![false; width]);
+        for x in 0..X_LENGTH as i32 {
+            self.set(x, 0, x != hole);
+        }
+    }
+}
+// ゲーム開始時に配置する盤面（パズル用、ブロックのあるマスはその色）
+#[derive(Resource)]
+struct InitialBoard(Option<Vec<Vec<Option<Color>>>>);
+
 //
-// Event
+// Resource: Config
 //
-#[derive(Event)]
-struct NewBlockEvent;
-#[derive(Event)]
-struct GameOverEvent;
+#[derive(Resource)]
+struct GameConfig {
+    // 初期盤面を読み込むパズルファイル
+    puzzle_file: Option<String>,
+    // ブロックに使う画像（assets フォルダからのパス、なければ単色で塗る）
+    block_texture: Option<String>,
+    // 盤面の背景
+    board_background: BoardBackground,
+    // ウィンドウ全体の背景に敷く画像（assets フォルダからのパス、なければ単色）
+    background_image: Option<String>,
+    // １マスの幅と高さ（ピクセル、違う値にすると縦長・横長のマスになる）
+    cell_width: u32,
+    cell_height: u32,
+    // 盤面をウィンドウの中央からずらす量（ピクセル、右と上が正）
+    board_offset_x: f32,
+    board_offset_y: f32,
+    // 制限時間内にラインを消さないとコンボが途切れるモード
+    combo_timer: bool,
+    // コンボの制限時間（ミリ秒）
+    combo_time_ms: u64,
+    // ブロックの縁取りを表示するか
+    block_outline: bool,
+    // ブロックの不透明度 (0.0 ～ 1.0)
+    block_opacity: f32,
+    // ブロックが１マス落ちる間隔（ミリ秒）
+    gravity_ms: u64,
+    // 落下間隔に掛ける倍率（[ / ] キーで調整する）
+    gravity_multiplier: f32,
+    // 左右移動を受け付ける間隔（ミリ秒）
+    input_interval_ms: u64,
+    // 回転を受け付ける最短の間隔（ミリ秒）
+    rotate_interval_ms: u64,
+    // 音量 (0.0 ～ 1.0)
+    master_volume: f32,
+    sfx_volume: f32,
+    music_volume: f32,
+    muted: bool,
+    // ライン消去後のブロックの落とし方
+    line_clear_gravity: LineClearGravity,
+    // ゲームモード
+    mode: GameMode,
+    // サバイバルモードでおじゃまブロックがせり上がる最初の間隔（ミリ秒）
+    garbage_interval_ms: u64,
+    // サバイバルモードで次にせり上がってくるおじゃまブロックと、それまでの残り時間を表示する
+    garbage_warning: bool,
+    // shrink モードで盤面が１列狭まる間隔（ミリ秒）
+    shrink_interval_ms: u64,
+    // 操作中のピースを明るく表示するか
+    highlight_active: bool,
+    // プレイ時間を表示するか
+    show_timer: bool,
+    // 盤面の右に積み上がりの高さのゲージを表示するか
+    height_meter: bool,
+    // 操作中のピースが落ちる位置から床までの列に影を付けるか（補助機能）
+    column_shadow: bool,
+    // 操作中のピースが落ちる位置に半透明のピースを表示するか
+    ghost_piece: bool,
+    // 半透明のピースの位置で固定したら揃う行を光らせるか
+    ghost_clear_rows: bool,
+    // 動かした・回した後この時間（ミリ秒）だけゴーストを表示し、操作しない間は薄くして消す（0 で常に表示）
+    ghost_fade_ms: u64,
+    // 積み上がり具合に応じて落下速度を変えるか
+    adaptive_gravity: bool,
+    // ↓キーの落とし方
+    soft_drop_style: SoftDropStyle,
+    // 一気に落とした時にその場で固定するか（false なら次の落下タイミングで固定）
+    hard_drop_locks: bool,
+    // ↑キーを押したままピースが出てきたら、回した向きで出す
+    initial_rotation: bool,
+    // プレイ中に R キーでやり直す時に、押し続けないとやり直さない（うっかり押した時のため）
+    confirm_restart: bool,
+    // 一気に落とす操作を使えるようにするか（false なら soft_drop_style に関わらず１マスずつ落とす）
+    hard_drop: bool,
+    // ゲームオーバーになる条件
+    topout_rule: TopOutRule,
+    // 高く積み上がった所で固定した時に別の効果音を鳴らすか
+    danger_lock_sound: bool,
+    // 点滅や揺れなどの動きを控えめにする
+    reduced_motion: bool,
+    // 垂直同期
+    vsync: bool,
+    // アンチエイリアスを切り、画像を拡大・縮小する時にぼかさない（画像の設定は次に起動した時から）
+    pixel_perfect: bool,
+    // フレームレートの上限（0 で無制限）
+    max_fps: u32,
+    // 文字の拡大率
+    ui_scale: f32,
+    // 表示する言語（en 以外は assets/lang/<言語>.txt から読み込む）
+    language: String,
+    // デバッグ機能（盤面エディタ、スローモーション）を有効にする
+    debug: bool,
+    // 記録しておくキー入力の数
+    input_log_size: usize,
+    // デバッグ用にピースの生成を 0 ～ この時間（ミリ秒）だけランダムに遅らせる（0 で遅らせない）
+    spawn_jitter_ms: u64,
+}
 
-// １マス当たりのサイズ
-const UNIT_WIDTH: u32 = 40;
-const UNIT_HEIGHT: u32 = 40;
+// ↓キーの落とし方
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum SoftDropStyle {
+    // 一番下まで一気に落とす（固定するかは hard_drop_locks で決める）
+    Instant,
+    // 押している間１マスずつ落とす
+    Gradual,
+}
 
-// 画面全体に表示したいマスのサイズ
-const X_LENGTH: u32 = 10;
-const Y_LENGTH: u32 = 18;
+impl std::str::FromStr for SoftDropStyle {
+    type Err = ();
 
-// 必要な画面サイズ
-const SCREEN_WIDTH: u32 = UNIT_WIDTH * X_LENGTH;
-const SCREEN_HEIGHT: u32 = UNIT_HEIGHT * Y_LENGTH;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "instant" => Ok(SoftDropStyle::Instant),
+            "gradual" => Ok(SoftDropStyle::Gradual),
+            _ => Err(()),
+        }
+    }
+}
 
-/**
- * メイン関数（エントリーポイント）
- */
-fn main() {
-    // ウィンドウ設定
-    let window_plugin = WindowPlugin {
-        primary_window: Some(Window {
-            resolution: WindowResolution::new((SCREEN_WIDTH + 5) as f32, (SCREEN_HEIGHT + 5) as f32),
-            title: "my tetris".into(),
-            mode: WindowMode::Windowed,
-            ..Window::default()
-        }),
-        .. Default::default()
-    };
+impl std::fmt::Display for SoftDropStyle {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SoftDropStyle::Instant => write!(f, "instant"),
+            SoftDropStyle::Gradual => write!(f, "gradual"),
+        }
+    }
+}
 
-    // アプリ作成
-    App::new() 
-        .insert_resource(BlockPatterns(vec![
-            vec![(0, 0), (0, -1), (0, 1), (0, 2)],  // I
-            vec![(0, 0), (0, -1), (0, 1), (-1, 1)], // L
-            vec![(0, 0), (0, -1), (0, 1), (1, 1)],  // 逆L
-            vec![(0, 0), (0, -1), (1, 0), (1, 1)],  // Z
-            vec![(0, 0), (1, 0), (0, 1), (1, -1)],  // 逆Z
-            vec![(0, 0), (0, 1), (1, 0), (1, 1)],   // 四角
-            vec![(0, 0), (-1, 0), (1, 0), (0, 1)],  // T
-        ]))
-        .insert_resource(GameTimer(Timer::new(
-            std::time::Duration::from_millis(400),
-            TimerMode::Repeating,
-        )))
-        .insert_resource(InputTimer(Timer::new(
-            std::time::Duration::from_millis(100),
-            TimerMode::Repeating,
-        )))
-        .insert_resource(GameBoard(vec![vec![false; 25]; 25]))
-        .add_plugins(DefaultPlugins.set(window_plugin))
-        .add_event::<NewBlockEvent>()
-        .add_event::<GameOverEvent>()
-        .add_systems(Startup, setup)
-        .add_systems(First, delete_line)
-        .add_systems(Update, (
-                spawn_block,
-                position_transform,
-                game_timer,
-                block_horizontal_move,
-                block_vertical_move,
-                block_rotate,
-                block_fall,
-                gameover,
-        ))
-    .run();
+// 盤面の背景
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum BoardBackground {
+    // 何も描かない（黒）
+    Plain,
+    // ２色の暗いマスを市松模様に並べる
+    Checkerboard,
 }
 
-/**
- * System: セットアップ
- */
-pub(crate) fn setup(mut commands: Commands, mut new_block_events: ResMut<Events<NewBlockEvent>>) {
-    // 2D カメラ エンティティの作成
-    commands.spawn(Camera2dBundle::default());
+impl std::str::FromStr for BoardBackground {
+    type Err = ();
 
-    // マテリアルカラーを用意する
-    commands.insert_resource(Materials {
-        colors: vec![
-            Color::rgb(0.25, 0.9, 0.39),
-            Color::rgb(0.85, 0.25, 0.35),
-            Color::rgb(0.27, 0.59, 0.82),
-            Color::rgb(0.89, 0.9, 0.27),
-            Color::rgb(0.13, 0.89, 0.94),
-            Color::rgb(0.94, 0.55, 0.27),
-        ],
-    });
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(BoardBackground::Plain),
+            "checkerboard" => Ok(BoardBackground::Checkerboard),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for BoardBackground {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BoardBackground::Plain => write!(f, "plain"),
+            BoardBackground::Checkerboard => write!(f, "checkerboard"),
+        }
+    }
+}
+
+// ゲームオーバーになる条件
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum TopOutRule {
+    // 次のピースが生成位置に置けない時（ブロックアウト）
+    BlockOut,
+    // ブロックアウトに加えて、ピースが丸ごと盤面より上で固定された時（ロックアウト）
+    LockOut,
+}
+
+impl std::str::FromStr for TopOutRule {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block_out" => Ok(TopOutRule::BlockOut),
+            "lock_out" => Ok(TopOutRule::LockOut),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for TopOutRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TopOutRule::BlockOut => write!(f, "block_out"),
+            TopOutRule::LockOut => write!(f, "lock_out"),
+        }
+    }
+}
+
+// ゲームモード
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum GameMode {
+    Normal,
+    // 固定したブロックがしばらくすると見えなくなる
+    Invisible,
+    // 置いたピースを元に戻せる練習モード
+    Practice,
+    // 下からおじゃまブロックがせり上がってくる
+    Survival,
+    // 盤面の端の列が埋まって、だんだん狭くなる
+    Shrink,
+    // 上下を逆さまに表示する（ピースは下から出てきて上に積み上がる）
+    Inverted,
+}
+
+impl std::str::FromStr for GameMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "normal" => Ok(GameMode::Normal),
+            "invisible" => Ok(GameMode::Invisible),
+            "practice" => Ok(GameMode::Practice),
+            "survival" => Ok(GameMode::Survival),
+            "shrink" => Ok(GameMode::Shrink),
+            "inverted" => Ok(GameMode::Inverted),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for GameMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            GameMode::Normal => write!(f, "normal"),
+            GameMode::Invisible => write!(f, "invisible"),
+            GameMode::Practice => write!(f, "practice"),
+            GameMode::Survival => write!(f, "survival"),
+            GameMode::Shrink => write!(f, "shrink"),
+            GameMode::Inverted => write!(f, "inverted"),
+        }
+    }
+}
+
+// ライン消去後のブロックの落とし方
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum LineClearGravity {
+    // 消えた行数だけそのまま下げる
+    Naive,
+    // つながったブロックの塊ごとに落とせるところまで落とす
+    Sticky,
+}
+
+impl std::str::FromStr for LineClearGravity {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "naive" => Ok(LineClearGravity::Naive),
+            "sticky" => Ok(LineClearGravity::Sticky),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for LineClearGravity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LineClearGravity::Naive => write!(f, "naive"),
+            LineClearGravity::Sticky => write!(f, "sticky"),
+        }
+    }
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        GameConfig {
+            puzzle_file: None,
+            block_texture: None,
+            board_background: BoardBackground::Plain,
+            background_image: None,
+            cell_width: UNIT_WIDTH,
+            cell_height: UNIT_HEIGHT,
+            board_offset_x: 0.0,
+            board_offset_y: 0.0,
+            combo_timer: false,
+            combo_time_ms: 3000,
+            block_outline: false,
+            block_opacity: 1.0,
+            gravity_ms: 400,
+            gravity_multiplier: 1.0,
+            input_interval_ms: 100,
+            rotate_interval_ms: 100,
+            master_volume: 0.8,
+            sfx_volume: 1.0,
+            music_volume: 0.6,
+            muted: false,
+            line_clear_gravity: LineClearGravity::Naive,
+            mode: GameMode::Normal,
+            garbage_interval_ms: 10000,
+            garbage_warning: true,
+            shrink_interval_ms: 30000,
+            highlight_active: true,
+            show_timer: false,
+            height_meter: false,
+            column_shadow: false,
+            ghost_piece: true,
+            ghost_clear_rows: false,
+            ghost_fade_ms: 0,
+            adaptive_gravity: false,
+            soft_drop_style: SoftDropStyle::Gradual,
+            hard_drop_locks: true,
+            initial_rotation: false,
+            confirm_restart: false,
+            hard_drop: true,
+            topout_rule: TopOutRule::BlockOut,
+            danger_lock_sound: true,
+            reduced_motion: false,
+            vsync: true,
+            pixel_perfect: false,
+            max_fps: 0,
+            ui_scale: 1.0,
+            language: "en".to_string(),
+            debug: false,
+            input_log_size: 600,
+            spawn_jitter_ms: 0,
+        }
+    }
+}
+
+impl GameConfig {
+    /**
+     * 設定ファイルの読み込み（ファイルが無ければデフォルト値）
+     * 書式: 1行に "key = value"、"#" から始まる行はコメント
+     */
+    fn load(path: &str) -> GameConfig {
+        let mut config = GameConfig::default();
+        let Ok(text) = std::fs::read_to_string(path) else {
+            return config;
+        };
+
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                eprintln!("config: invalid line: {}", line);
+                continue;
+            };
+            config.apply(key.trim(), value.trim());
+        }
+
+        config.validate();
+        config
+    }
+
+    /**
+     * 範囲外の設定値をデフォルト値に戻す（0 ミリ秒のタイマーなど、ゲームが動かなくなる値を起動前に弾く）
+     */
+    fn validate(&mut self) {
+        let default = GameConfig::default();
+        self.combo_time_ms = validate_range("combo_time_ms", self.combo_time_ms, 1, u64::MAX, default.combo_time_ms);
+        self.block_opacity = validate_range("block_opacity", self.block_opacity, 0.0, 1.0, default.block_opacity);
+        self.cell_width = validate_range("cell_width", self.cell_width, MIN_CELL_SIZE, MAX_CELL_SIZE, default.cell_width);
+        self.cell_height = validate_range("cell_height", self.cell_height, MIN_CELL_SIZE, MAX_CELL_SIZE, default.cell_height);
+        self.board_offset_x = validate_range("board_offset_x", self.board_offset_x, f32::MIN, f32::MAX, default.board_offset_x);
+        self.board_offset_y = validate_range("board_offset_y", self.board_offset_y, f32::MIN, f32::MAX, default.board_offset_y);
+        self.gravity_ms = validate_range("gravity_ms", self.gravity_ms, 1, u64::MAX, default.gravity_ms);
+        self.gravity_multiplier = validate_range(
+            "gravity_multiplier",
+            self.gravity_multiplier,
+            MIN_GRAVITY_MULTIPLIER,
+            MAX_GRAVITY_MULTIPLIER,
+            default.gravity_multiplier,
+        );
+        self.input_interval_ms = validate_range("input_interval_ms", self.input_interval_ms, 1, u64::MAX, default.input_interval_ms);
+        self.rotate_interval_ms =
+            validate_range("rotate_interval_ms", self.rotate_interval_ms, 0, MAX_ROTATE_INTERVAL_MS, default.rotate_interval_ms);
+        self.master_volume = validate_range("master_volume", self.master_volume, 0.0, 1.0, default.master_volume);
+        self.sfx_volume = validate_range("sfx_volume", self.sfx_volume, 0.0, 1.0, default.sfx_volume);
+        self.music_volume = validate_range("music_volume", self.music_volume, 0.0, 1.0, default.music_volume);
+        self.garbage_interval_ms = validate_range("garbage_interval_ms", self.garbage_interval_ms, 1, u64::MAX, default.garbage_interval_ms);
+        self.shrink_interval_ms = validate_range("shrink_interval_ms", self.shrink_interval_ms, 1, u64::MAX, default.shrink_interval_ms);
+        self.ghost_fade_ms = validate_range("ghost_fade_ms", self.ghost_fade_ms, 0, MAX_GHOST_FADE_MS, default.ghost_fade_ms);
+        // 0 は無制限なので範囲の外でもそのまま使う
+        if self.max_fps != 0 {
+            self.max_fps = validate_range("max_fps", self.max_fps, MIN_FPS, MAX_FPS, default.max_fps);
+        }
+        self.ui_scale = validate_range("ui_scale", self.ui_scale, MIN_UI_SCALE, MAX_UI_SCALE, default.ui_scale);
+        self.input_log_size = validate_range("input_log_size", self.input_log_size, 0, MAX_INPUT_LOG_SIZE, default.input_log_size);
+        self.spawn_jitter_ms = validate_range("spawn_jitter_ms", self.spawn_jitter_ms, 0, MAX_SPAWN_JITTER_MS, default.spawn_jitter_ms);
+    }
+
+    /**
+     * 盤面の中心のワールド座標（盤面に合わせて置くものはすべてこれを基準にする）
+     */
+    fn board_offset(&self) -> Vec2 {
+        Vec2::new(self.board_offset_x, self.board_offset_y)
+    }
+
+    /**
+     * gravity_multiplier を掛けた落下間隔
+     */
+    fn gravity_interval(&self) -> std::time::Duration {
+        let gravity_ms = (self.gravity_ms as f32 * self.gravity_multiplier) as u64;
+        std::time::Duration::from_millis(gravity_ms.max(1))
+    }
+
+    /**
+     * 実際に使う↓キーの落とし方（一気に落とす操作を使えない時は常に１マスずつ）
+     */
+    fn effective_soft_drop_style(&self) -> SoftDropStyle {
+        if self.hard_drop {
+            self.soft_drop_style
+        } else {
+            SoftDropStyle::Gradual
+        }
+    }
+
+    /**
+     * ウィンドウの幅（得点などを表示する余白と高さのゲージの分は盤面の左右に同じだけ足して、盤面を中央に保つ）
+     */
+    fn window_width(&self) -> f32 {
+        let meter_space = if self.height_meter { (HEIGHT_METER_GAP * 2.0 + HEIGHT_METER_WIDTH) * 2.0 } else { 0.0 };
+        self.board_size().x + 5.0 + meter_space + SIDE_PANEL_WIDTH * 2.0
+    }
+
+    /**
+     * ウィンドウの高さ
+     */
+    fn window_height(&self) -> f32 {
+        self.board_size().y + 5.0
+    }
+
+    /**
+     * １マスの大きさ（ピクセル）
+     */
+    fn cell_size(&self) -> Vec2 {
+        Vec2::new(self.cell_width as f32, self.cell_height as f32)
+    }
+
+    /**
+     * 盤面の +y（ピースが積み上がる向き）がワールド座標で上なら 1.0、下なら -1.0
+     * inverted モードでも盤面の処理はそのままで、表示だけを上下逆さまにする
+     */
+    fn board_up(&self) -> f32 {
+        if self.mode == GameMode::Inverted {
+            -1.0
+        } else {
+            1.0
+        }
+    }
+
+    /**
+     * 見えている盤面全体の大きさ（ピクセル）
+     */
+    fn board_size(&self) -> Vec2 {
+        self.cell_size() * Vec2::new(X_LENGTH as f32, Y_LENGTH as f32)
+    }
+
+    /**
+     * ホールドの表示の中心（盤面の左の余白の、得点の表示の下）
+     */
+    fn hold_preview_center(&self) -> Vec2 {
+        let board_size = self.board_size();
+        self.board_offset()
+            + Vec2::new(
+                -(board_size.x / 2.0 + BORDER_WIDTH + SIDE_PANEL_WIDTH / 2.0),
+                board_size.y / 2.0 - HOLD_TOP_MARGIN,
+            )
+    }
+
+    /**
+     * 次のピースの表示の中心（盤面の右の余白の上の方。高さのゲージを表示する時はその外側）
+     */
+    fn preview_center(&self) -> Vec2 {
+        let meter_space = if self.height_meter { HEIGHT_METER_GAP * 2.0 + HEIGHT_METER_WIDTH } else { 0.0 };
+        let board_size = self.board_size();
+        self.board_offset()
+            + Vec2::new(
+                board_size.x / 2.0 + BORDER_WIDTH + meter_space + SIDE_PANEL_WIDTH / 2.0,
+                board_size.y / 2.0 - PREVIEW_TOP_MARGIN,
+            )
+    }
+
+    /**
+     * 設定項目を１つ反映する
+     */
+    fn apply(&mut self, key: &str, value: &str) {
+        match key {
+            "puzzle_file" => self.puzzle_file = Some(value.to_string()),
+            "block_texture" => self.block_texture = Some(value.to_string()),
+            "board_background" => self.board_background = parse_config_value(key, value, self.board_background),
+            "background_image" => self.background_image = Some(value.to_string()),
+            "cell_width" => self.cell_width = parse_config_value(key, value, self.cell_width),
+            "cell_height" => self.cell_height = parse_config_value(key, value, self.cell_height),
+            "board_offset_x" => self.board_offset_x = parse_config_value(key, value, self.board_offset_x),
+            "board_offset_y" => self.board_offset_y = parse_config_value(key, value, self.board_offset_y),
+            "combo_timer" => self.combo_timer = parse_config_value(key, value, self.combo_timer),
+            "combo_time_ms" => self.combo_time_ms = parse_config_value(key, value, self.combo_time_ms),
+            "block_outline" => self.block_outline = parse_config_value(key, value, self.block_outline),
+            "block_opacity" => self.block_opacity = parse_config_value(key, value, self.block_opacity),
+            "gravity_ms" => self.gravity_ms = parse_config_value(key, value, self.gravity_ms),
+            "gravity_multiplier" => self.gravity_multiplier = parse_config_value(key, value, self.gravity_multiplier),
+            "input_interval_ms" => self.input_interval_ms = parse_config_value(key, value, self.input_interval_ms),
+            "rotate_interval_ms" => self.rotate_interval_ms = parse_config_value(key, value, self.rotate_interval_ms),
+            "master_volume" => self.master_volume = parse_config_value(key, value, self.master_volume),
+            "sfx_volume" => self.sfx_volume = parse_config_value(key, value, self.sfx_volume),
+            "music_volume" => self.music_volume = parse_config_value(key, value, self.music_volume),
+            "muted" => self.muted = parse_config_value(key, value, self.muted),
+            "line_clear_gravity" => self.line_clear_gravity = parse_config_value(key, value, self.line_clear_gravity),
+            "mode" => self.mode = parse_config_value(key, value, self.mode),
+            "garbage_interval_ms" => self.garbage_interval_ms = parse_config_value(key, value, self.garbage_interval_ms),
+            "garbage_warning" => self.garbage_warning = parse_config_value(key, value, self.garbage_warning),
+            "shrink_interval_ms" => self.shrink_interval_ms = parse_config_value(key, value, self.shrink_interval_ms),
+            "highlight_active" => self.highlight_active = parse_config_value(key, value, self.highlight_active),
+            "show_timer" => self.show_timer = parse_config_value(key, value, self.show_timer),
+            "height_meter" => self.height_meter = parse_config_value(key, value, self.height_meter),
+            "column_shadow" => self.column_shadow = parse_config_value(key, value, self.column_shadow),
+            "ghost_piece" => self.ghost_piece = parse_config_value(key, value, self.ghost_piece),
+            "ghost_clear_rows" => self.ghost_clear_rows = parse_config_value(key, value, self.ghost_clear_rows),
+            "ghost_fade_ms" => self.ghost_fade_ms = parse_config_value(key, value, self.ghost_fade_ms),
+            "adaptive_gravity" => self.adaptive_gravity = parse_config_value(key, value, self.adaptive_gravity),
+            "soft_drop_style" => self.soft_drop_style = parse_config_value(key, value, self.soft_drop_style),
+            "hard_drop_locks" => self.hard_drop_locks = parse_config_value(key, value, self.hard_drop_locks),
+            "initial_rotation" => self.initial_rotation = parse_config_value(key, value, self.initial_rotation),
+            "confirm_restart" => self.confirm_restart = parse_config_value(key, value, self.confirm_restart),
+            "hard_drop" => self.hard_drop = parse_config_value(key, value, self.hard_drop),
+            "topout_rule" => self.topout_rule = parse_config_value(key, value, self.topout_rule),
+            "danger_lock_sound" => self.danger_lock_sound = parse_config_value(key, value, self.danger_lock_sound),
+            "reduced_motion" => self.reduced_motion = parse_config_value(key, value, self.reduced_motion),
+            "vsync" => self.vsync = parse_config_value(key, value, self.vsync),
+            "pixel_perfect" => self.pixel_perfect = parse_config_value(key, value, self.pixel_perfect),
+            "max_fps" => self.max_fps = parse_config_value(key, value, self.max_fps),
+            "ui_scale" => self.ui_scale = parse_config_value(key, value, self.ui_scale),
+            "language" => self.language = value.to_string(),
+            "debug" => self.debug = parse_config_value(key, value, self.debug),
+            "input_log_size" => self.input_log_size = parse_config_value(key, value, self.input_log_size),
+            "spawn_jitter_ms" => self.spawn_jitter_ms = parse_config_value(key, value, self.spawn_jitter_ms),
+            _ => eprintln!("config: unknown key: {}", key),
+        }
+    }
+
+    /**
+     * 設定ファイルへの書き出し
+     */
+    fn save(&self, path: &str) -> std::io::Result<()> {
+        let mut text = String::new();
+        if let Some(puzzle_file) = &self.puzzle_file {
+            text.push_str(&format!("puzzle_file = {}\n", puzzle_file));
+        }
+        if let Some(block_texture) = &self.block_texture {
+            text.push_str(&format!("block_texture = {}\n", block_texture));
+        }
+        text.push_str(&format!("combo_timer = {}\n", self.combo_timer));
+        text.push_str(&format!("combo_time_ms = {}\n", self.combo_time_ms));
+        text.push_str(&format!("block_outline = {}\n", self.block_outline));
+        text.push_str(&format!("block_opacity = {}\n", self.block_opacity));
+        text.push_str(&format!("board_background = {}\n", self.board_background));
+        if let Some(background_image) = &self.background_image {
+            text.push_str(&format!("background_image = {}\n", background_image));
+        }
+        text.push_str(&format!("cell_width = {}\n", self.cell_width));
+        text.push_str(&format!("cell_height = {}\n", self.cell_height));
+        text.push_str(&format!("board_offset_x = {}\n", self.board_offset_x));
+        text.push_str(&format!("board_offset_y = {}\n", self.board_offset_y));
+        text.push_str(&format!("gravity_ms = {}\n", self.gravity_ms));
+        text.push_str(&format!("gravity_multiplier = {}\n", self.gravity_multiplier));
+        text.push_str(&format!("input_interval_ms = {}\n", self.input_interval_ms));
+        text.push_str(&format!("rotate_interval_ms = {}\n", self.rotate_interval_ms));
+        text.push_str(&format!("master_volume = {}\n", self.master_volume));
+        text.push_str(&format!("sfx_volume = {}\n", self.sfx_volume));
+        text.push_str(&format!("music_volume = {}\n", self.music_volume));
+        text.push_str(&format!("muted = {}\n", self.muted));
+        text.push_str(&format!("line_clear_gravity = {}\n", self.line_clear_gravity));
+        text.push_str(&format!("mode = {}\n", self.mode));
+        text.push_str(&format!("garbage_interval_ms = {}\n", self.garbage_interval_ms));
+        text.push_str(&format!("garbage_warning = {}\n", self.garbage_warning));
+        text.push_str(&format!("shrink_interval_ms = {}\n", self.shrink_interval_ms));
+        text.push_str(&format!("highlight_active = {}\n", self.highlight_active));
+        text.push_str(&format!("show_timer = {}\n", self.show_timer));
+        text.push_str(&format!("height_meter = {}\n", self.height_meter));
+        text.push_str(&format!("column_shadow = {}\n", self.column_shadow));
+        text.push_str(&format!("ghost_piece = {}\n", self.ghost_piece));
+        text.push_str(&format!("ghost_clear_rows = {}\n", self.ghost_clear_rows));
+        text.push_str(&format!("ghost_fade_ms = {}\n", self.ghost_fade_ms));
+        text.push_str(&format!("adaptive_gravity = {}\n", self.adaptive_gravity));
+        text.push_str(&format!("soft_drop_style = {}\n", self.soft_drop_style));
+        text.push_str(&format!("hard_drop_locks = {}\n", self.hard_drop_locks));
+        text.push_str(&format!("initial_rotation = {}\n", self.initial_rotation));
+        text.push_str(&format!("confirm_restart = {}\n", self.confirm_restart));
+        text.push_str(&format!("hard_drop = {}\n", self.hard_drop));
+        text.push_str(&format!("topout_rule = {}\n", self.topout_rule));
+        text.push_str(&format!("danger_lock_sound = {}\n", self.danger_lock_sound));
+        text.push_str(&format!("reduced_motion = {}\n", self.reduced_motion));
+        text.push_str(&format!("vsync = {}\n", self.vsync));
+        text.push_str(&format!("pixel_perfect = {}\n", self.pixel_perfect));
+        text.push_str(&format!("max_fps = {}\n", self.max_fps));
+        text.push_str(&format!("ui_scale = {}\n", self.ui_scale));
+        text.push_str(&format!("language = {}\n", self.language));
+        text.push_str(&format!("debug = {}\n", self.debug));
+        text.push_str(&format!("input_log_size = {}\n", self.input_log_size));
+        text.push_str(&format!("spawn_jitter_ms = {}\n", self.spawn_jitter_ms));
+
+        std::fs::write(path, text)
+    }
+}
+
+//
+// Resource: Audio
+//
+#[derive(Resource)]
+struct AudioSettings {
+    master: f32,
+    sfx: f32,
+    music: f32,
+    muted: bool,
+}
+
+impl AudioSettings {
+    fn from_config(config: &GameConfig) -> AudioSettings {
+        AudioSettings {
+            master: config.master_volume,
+            sfx: config.sfx_volume,
+            music: config.music_volume,
+            muted: config.muted,
+        }
+    }
+
+    /**
+     * 実際に再生する音量
+     */
+    fn volume(&self, is_music: bool) -> f32 {
+        if self.muted {
+            return 0.0;
+        }
+        self.master * if is_music { self.music } else { self.sfx }
+    }
+
+    /**
+     * 効果音の再生設定（再生後にエンティティを削除）
+     */
+    fn sfx_playback(&self) -> PlaybackSettings {
+        PlaybackSettings::DESPAWN.with_volume(Volume::new_relative(self.volume(false)))
+    }
+
+    /**
+     * BGM の再生設定（ループ再生）
+     */
+    fn music_playback(&self) -> PlaybackSettings {
+        PlaybackSettings::LOOP.with_volume(Volume::new_relative(self.volume(true)))
+    }
+}
+
+// BGM の種類
+#[derive(Clone, Copy, PartialEq)]
+enum MusicKind {
+    Menu,
+    Playing,
+}
+
+// ファイルが無い BGM は None（流さない）
+#[derive(Resource)]
+struct MusicTracks {
+    menu: Option<Handle<AudioSource>>,
+    playing: Option<Handle<AudioSource>>,
+    // 現在流している BGM
+    current: Option<MusicKind>,
+}
+
+// 効果音（ファイルが無い効果音は None で、鳴らさない）
+#[derive(Resource)]
+struct SoundEffects {
+    // ピースを固定した時
+    lock: Option<Handle<AudioSource>>,
+    // 高く積み上がった所でピースを固定した時
+    lock_danger: Option<Handle<AudioSource>>,
+}
+
+// ブロックに使う画像（block_texture が無い・ファイルが無い時はデフォルトの白一色）
+#[derive(Resource)]
+struct BlockTexture(Handle<Image>);
+
+impl FromWorld for BlockTexture {
+    fn from_world(world: &mut World) -> Self {
+        let config = world.resource::<GameConfig>();
+        let asset_server = world.resource::<AssetServer>();
+        let texture = config
+            .block_texture
+            .as_ref()
+            .and_then(|path| load_asset_if_exists(asset_server, path));
+        BlockTexture(texture.unwrap_or_default())
+    }
+}
+
+//
+// Resource: Frame Limiter
+//
+// 前のフレームが終わった時刻
+#[derive(Resource)]
+struct FrameLimiter(std::time::Instant);
+
+//
+// Resource: Options Menu
+//
+// 設定メニューの項目
+#[derive(Clone, Copy, PartialEq)]
+enum OptionItem {
+    Gravity,
+    InputInterval,
+    ComboTimer,
+    BlockOutline,
+    BlockOpacity,
+    Volume,
+    SoftDrop,
+    VSync,
+    PixelPerfect,
+    MaxFps,
+    UiScale,
+}
+
+const OPTION_ITEMS: [OptionItem; 11] = [
+    OptionItem::Gravity,
+    OptionItem::InputInterval,
+    OptionItem::ComboTimer,
+    OptionItem::BlockOutline,
+    OptionItem::BlockOpacity,
+    OptionItem::Volume,
+    OptionItem::SoftDrop,
+    OptionItem::VSync,
+    OptionItem::PixelPerfect,
+    OptionItem::MaxFps,
+    OptionItem::UiScale,
+];
+
+// フレームレート上限の選択肢（0 は無制限）
+const FPS_CHOICES: [u32; 5] = [0, 30, 60, 120, 144];
+
+// 選択中の項目
+#[derive(Resource, Default)]
+struct OptionsMenu {
+    cursor: usize,
+}
+
+/**
+ * 設定値の変換（変換できなければ現在の値のまま）
+ */
+fn parse_config_value<T: std::str::FromStr>(key: &str, value: &str, current: T) -> T {
+    value.parse().unwrap_or_else(|_| {
+        eprintln!("config: invalid value for {}: {}", key, value);
+        current
+    })
+}
+
+/**
+ * 設定値が min ～ max の範囲内か確認する（範囲外や NaN ならデフォルト値）
+ */
+fn validate_range<T: PartialOrd + std::fmt::Display>(key: &str, value: T, min: T, max: T, default: T) -> T {
+    if value >= min && value <= max {
+        return value;
+    }
+    eprintln!("config: {} must be between {} and {}, got {}; using {}", key, min, max, value, default);
+    default
+}
+
+//
+// State
+//
+#[derive(States, Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+enum GameState {
+    #[default]
+    Playing,
+    Paused,
+    Options,
+    GameOver,
+}
+
+//
+// Event
+//
+#[derive(Event)]
+struct NewBlockEvent;
+#[derive(Event)]
+struct GameOverEvent;
+// 落下のタイミング（game_timer が GameTimer の周期ごとに通知する）
+// block_fall（Update）と delete_line（次のフレームの First）がそれぞれ別々に受け取る
+#[derive(Event)]
+struct GravityTickEvent;
+
+// １マス当たりのサイズ（cell_width / cell_height のデフォルト値）と設定できる範囲
+const UNIT_WIDTH: u32 = 40;
+const UNIT_HEIGHT: u32 = 40;
+const MIN_CELL_SIZE: u32 = 8;
+const MAX_CELL_SIZE: u32 = 120;
+
+// 画面全体に表示したいマスのサイズ
+const X_LENGTH: u32 = 10;
+const Y_LENGTH: u32 = 18;
+
+// ブロックの縁取りの太さ
+const OUTLINE_WIDTH: u32 = 2;
+
+// 描画の重なり順
+// 2D カメラは z が -0.1 より奥を描画しないので、背景画像はその手前に置く
+const BACKGROUND_IMAGE_Z: f32 = -0.05;
+const BACKGROUND_Z: f32 = 0.0;
+const SHADOW_Z: f32 = 0.25;
+const GHOST_Z: f32 = 0.75;
+const BORDER_Z: f32 = 0.5;
+const BLOCK_Z: f32 = 1.0;
+
+// 盤面の枠の太さと色
+const BORDER_WIDTH: f32 = 2.0;
+const BORDER_COLOR: Color = Color::rgb(0.35, 0.35, 0.35);
+const DANGER_COLOR: Color = Color::rgb(0.9, 0.1, 0.1);
+
+// この高さ以上まで積み上がった所で固定すると危険を知らせる効果音を鳴らす
+const DANGER_STACK_HEIGHT: i32 = Y_LENGTH as i32 - 4;
+
+// 高さのゲージの太さと盤面の枠との間隔
+const HEIGHT_METER_WIDTH: f32 = 8.0;
+const HEIGHT_METER_GAP: f32 = 6.0;
+
+// 得点などを表示する盤面の横の余白の幅と、盤面の枠との間隔
+const SIDE_PANEL_WIDTH: f32 = 120.0;
+const SIDE_PANEL_GAP: f32 = 8.0;
+
+// 次のピースの表示の１マスの大きさと、盤面の上端から表示の中心までの距離
+const PREVIEW_CELL_SIZE: f32 = 20.0;
+const PREVIEW_TOP_MARGIN: f32 = 70.0;
+// 盤面の上端からホールドの表示の中心までの距離（得点の表示の下に置く）
+const HOLD_TOP_MARGIN: f32 = 150.0;
+
+// おじゃまブロックの予告の列の太さ
+const GARBAGE_WARNING_WIDTH: f32 = 6.0;
+// １回にせり上がってくるおじゃまブロックの行数
+const GARBAGE_LINES_PER_RISE: u32 = 1;
+
+// おすすめの置き場所を示すマスの色
+const HINT_COLOR: Color = Color::rgba(1.0, 1.0, 1.0, 0.35);
+
+// 半透明のピースの位置で固定したら揃う行の色
+const GHOST_CLEAR_COLOR: Color = Color::rgba(1.0, 1.0, 1.0, 0.15);
+
+// 列の影の色
+const SHADOW_COLOR: Color = Color::rgba(1.0, 1.0, 1.0, 0.08);
+
+// 市松模様の背景の２色
+const CHECKER_COLORS: [Color; 2] = [Color::rgb(0.06, 0.06, 0.08), Color::rgb(0.1, 0.1, 0.13)];
+
+// 生成したピースを拡大する演出の時間（秒）と最初の大きさ
+const SPAWN_ANIMATION_SECS: f32 = 0.1;
+const SPAWN_ANIMATION_START_SCALE: f32 = 0.5;
+
+// ライン消去で残ったブロックが下がる演出の時間（秒）
+const COLLAPSE_ANIMATION_SECS: f32 = 0.15;
+
+// パズルファイルで色を指定してブロックを置く文字と色（'#' は PUZZLE_BLOCK_COLOR）
+const PUZZLE_BLOCK_COLOR: Color = Color::GRAY;
+const PUZZLE_COLORS: [(char, Color); 7] = [
+    ('R', Color::rgb(0.85, 0.25, 0.35)),
+    ('G', Color::rgb(0.25, 0.9, 0.39)),
+    ('B', Color::rgb(0.27, 0.59, 0.82)),
+    ('Y', Color::rgb(0.89, 0.9, 0.27)),
+    ('C', Color::rgb(0.13, 0.89, 0.94)),
+    ('O', Color::rgb(0.94, 0.55, 0.27)),
+    ('P', Color::rgb(0.62, 0.36, 0.86)),
+];
+
+// invisible モードでブロックが消え始めるまでの時間と消えるまでの時間（秒）
+const INVISIBLE_DELAY_SECS: f32 = 2.0;
+const INVISIBLE_FADE_SECS: f32 = 0.5;
+
+// 落下間隔の上限と下限（ミリ秒）
+const MIN_GRAVITY_MS: u64 = 80;
+const MAX_GRAVITY_MS: u64 = 2000;
+
+// 落下間隔の倍率の範囲と、[ / ] キー１回で変える割合
+const MIN_GRAVITY_MULTIPLIER: f32 = 0.25;
+const MAX_GRAVITY_MULTIPLIER: f32 = 4.0;
+const GRAVITY_MULTIPLIER_STEP: f32 = 1.1;
+
+// 文字の拡大率の範囲
+const MIN_UI_SCALE: f32 = 0.5;
+const MAX_UI_SCALE: f32 = 2.0;
+
+// 回転を受け付ける間隔・ゴーストを薄くしていく時間・ピースの生成を遅らせる時間の上限（ミリ秒）
+const MAX_ROTATE_INTERVAL_MS: u64 = 1000;
+const MAX_GHOST_FADE_MS: u64 = 10_000;
+const MAX_SPAWN_JITTER_MS: u64 = 5000;
+
+// フレームレートの上限に指定できる範囲（0 は無制限）
+const MIN_FPS: u32 = 10;
+const MAX_FPS: u32 = 1000;
+
+// おじゃまブロックがせり上がるたびに間隔を縮める割合と、間隔の下限（ミリ秒）
+const GARBAGE_SPEEDUP: f32 = 0.95;
+const MIN_GARBAGE_INTERVAL_MS: u64 = 2000;
+
+// shrink モードで残す列の数（これより狭くなるとゲームオーバー）
+const MIN_PLAYFIELD_WIDTH: i32 = 4;
+// 壁の色
+const WALL_COLOR: Color = Color::rgb(0.3, 0.3, 0.3);
+
+// 生成位置が塞がっている時に上にずらす最大のマス数
+const SPAWN_NUDGE_LIMIT: i32 = 2;
+
+// 元に戻せるピースの数
+const UNDO_LIMIT: usize = 20;
+
+// BGM の切り替えにかける時間（ミリ秒）
+const MUSIC_FADE_MS: u64 = 800;
+
+// confirm_restart の時に R キーを押し続ける時間
+const RESTART_HOLD_MS: u64 = 500;
+
+// 同時に消した行数（0 ～ 4 行）ごとの得点
+const LINE_CLEAR_SCORES: [u32; 5] = [0, 100, 300, 500, 800];
+
+// 設定ファイルのパス
+const CONFIG_PATH: &str = "config.txt";
+
+// 画像・音声・フォントを置くディレクトリ
+const ASSETS_DIR: &str = "assets";
+
+// 得点の表示に使うフォント（assets フォルダからのパス、無ければ標準のフォント）
+const SCORE_FONT_PATH: &str = "fonts/DejaVuSansMono-Bold.ttf";
+
+// 言語ごとの文字列ファイルを置くディレクトリ
+const LANG_DIR: &str = "assets/lang";
+
+// 英語の文字列（デフォルト。他の言語のファイルに無い ID もこれで表示する）
+const DEFAULT_UI_STRINGS: &[(&str, &str)] = &[
+    ("window_title", "my tetris"),
+    ("game_over", "GAME OVER"),
+    ("time", "TIME"),
+    ("assisted", "ASSISTED"),
+    ("seed", "SEED"),
+    ("retry", "ENTER : RETRY"),
+    ("retry_same_seed", "S : RETRY SAME SEED"),
+    ("paused", "PAUSED"),
+    ("open_options", "O : OPTIONS"),
+    ("speed", "SPEED"),
+    ("slow", "SLOW"),
+    ("garbage", "GARBAGE"),
+    ("score", "SCORE"),
+    ("next", "NEXT"),
+    ("hold", "HOLD"),
+    ("on", "ON"),
+    ("off", "OFF"),
+    ("option_gravity", "GRAVITY"),
+    ("option_move_repeat", "MOVE REPEAT"),
+    ("option_combo_timer", "COMBO TIMER"),
+    ("option_outline", "OUTLINE"),
+    ("option_opacity", "OPACITY"),
+    ("option_volume", "VOLUME"),
+    ("option_soft_drop", "SOFT DROP"),
+    ("option_vsync", "VSYNC"),
+    ("option_crisp", "CRISP"),
+    ("option_max_fps", "MAX FPS"),
+    ("option_text_size", "TEXT SIZE"),
+    ("soft_drop_instant", "INSTANT"),
+    ("soft_drop_gradual", "GRADUAL"),
+    ("no_hard_drop", "GRADUAL (NO HARD DROP)"),
+    ("unlimited", "UNLIMITED"),
+];
+
+// 設定メニューの項目名の幅（値の列を揃える）
+const OPTION_LABEL_WIDTH: usize = 12;
+
+// デバッグ用のスローモーションの速さ
+const SLOW_MOTION_SCALE: f32 = 0.25;
+
+// デバッグ用エディタで盤面を書き出すパス
+const PUZZLE_EXPORT_PATH: &str = "puzzle_export.txt";
+
+// 記録しておくキー入力の数の上限
+const MAX_INPUT_LOG_SIZE: usize = 100_000;
+
+// ゲームの状態を JSON で書き出すパスと形式のバージョン（形式を変えたらバージョンを上げること）
+const STATE_EXPORT_PATH: &str = "state_export.json";
+const STATE_EXPORT_VERSION: u32 = 1;
+
+// キー入力の記録を書き出すパス
+const INPUT_LOG_PATH: &str = "input_log.txt";
+
+/**
+ * メイン関数（エントリーポイント）
+ */
+fn main() {
+    // 設定の読み込み
+    let config = GameConfig::load(CONFIG_PATH);
+    let ui_strings = load_ui_strings(&config.language);
+
+    // ウィンドウ設定
+    let window_plugin = WindowPlugin {
+        primary_window: Some(Window {
+            resolution: WindowResolution::new(config.window_width(), config.window_height()),
+            title: ui_strings.get("window_title").into(),
+            mode: WindowMode::Windowed,
+            present_mode: present_mode(config.vsync),
+            ..Window::default()
+        }),
+        .. Default::default()
+    };
+
+    // パズルの初期盤面の読み込み
+    let initial_board = load_puzzle(&config).unwrap_or_else(|message| {
+        eprintln!("{}", message);
+        None
+    });
+
+    let combo_time = std::time::Duration::from_millis(config.combo_time_ms);
+    let seed: u64 = rand::thread_rng().gen();
+    let gravity_time = config.gravity_interval();
+    let input_time = std::time::Duration::from_millis(config.input_interval_ms);
+    let rotate_time = std::time::Duration::from_millis(config.rotate_interval_ms);
+    // 最初の回転はすぐに受け付ける
+    let mut rotate_cooldown = Timer::new(rotate_time, TimerMode::Once);
+    rotate_cooldown.tick(rotate_time);
+    let garbage_time = std::time::Duration::from_millis(config.garbage_interval_ms);
+    let shrink_time = std::time::Duration::from_millis(config.shrink_interval_ms);
+    // 画像の拡大・縮小の方法は起動時にしか決められない
+    let image_plugin = if config.pixel_perfect { ImagePlugin::default_nearest() } else { ImagePlugin::default_linear() };
+
+    // アプリ作成
+    App::new() 
+        .insert_resource(BlockPatterns(standard_block_patterns()))
+        .insert_resource(GameTimer(Timer::new(
+            gravity_time,
+            TimerMode::Repeating,
+        )))
+        .insert_resource(InputTimer(Timer::new(
+            input_time,
+            TimerMode::Repeating,
+        )))
+        .insert_resource(RotateCooldown(rotate_cooldown))
+        .insert_resource(RestartHold(Timer::new(
+            std::time::Duration::from_millis(RESTART_HOLD_MS),
+            TimerMode::Once,
+        )))
+        .insert_resource(GhostFade(Timer::new(
+            std::time::Duration::from_millis(config.ghost_fade_ms),
+            TimerMode::Once,
+        )))
+        .insert_resource(PieceCounter::default())
+        .insert_resource(LockHistory::default())
+        .insert_resource(PracticeCheckpoint::default())
+        .insert_resource(GameRng(StdRng::seed_from_u64(seed)))
+        .insert_resource(PieceBag::default())
+        .insert_resource(NextPiece::default())
+        .insert_resource(Hold::default())
+        .insert_resource(RunSeed(seed))
+        .insert_resource(RunAssisted::default())
+        .insert_resource(ComboTimer(Timer::new(combo_time, TimerMode::Once)))
+        .insert_resource(Combo::default())
+        .insert_resource(Score::default())
+        .insert_resource(RunClock::default())
+        .insert_resource(ui_strings)
+        .insert_resource(TimeScale(1.0))
+        .insert_resource(CoordinateColors::default())
+        .insert_resource(SpawnDelay::default())
+        .insert_resource(InputLog {
+            frame: 0,
+            capacity: config.input_log_size,
+            entries: std::collections::VecDeque::with_capacity(config.input_log_size),
+        })
+        .insert_resource(GarbageTimer(Timer::new(garbage_time, TimerMode::Repeating)))
+        .insert_resource(SurvivalTime::default())
+        .insert_resource(PlayfieldWalls::default())
+        .insert_resource(ShrinkTimer(Timer::new(shrink_time, TimerMode::Repeating)))
+        .insert_resource(GameBoard::new())
+        .insert_resource(InitialBoard(initial_board))
+        .insert_resource(OptionsMenu::default())
+        .insert_resource(FrameLimiter(std::time::Instant::now()))
+        .insert_resource(msaa(config.pixel_perfect))
+        .insert_resource(AudioSettings::from_config(&config))
+        .insert_resource(config)
+        .add_plugins(DefaultPlugins.set(window_plugin).set(image_plugin))
+        // 読み込みに AssetServer と設定を使うので、プラグインの追加の後に作る
+        .init_resource::<BlockTexture>()
+        .add_event::<NewBlockEvent>()
+        .add_event::<GameOverEvent>()
+        .add_event::<GravityTickEvent>()
+        .add_state::<GameState>()
+        .add_systems(Startup, (setup, setup_score_ui, setup_next_piece_ui, start_first_game, setup_music, setup_sound_effects))
+        .add_systems(First, delete_line.run_if(in_state(GameState::Playing)))
+        .add_systems(Update, (
+                // ピースを取り除いてから生成を要求するシステムの後は、取り除いたのを反映してから生成する
+                // 生成したフレームで落下タイマーが終わらないように、タイマーを進める前に生成する
+                (
+                    apply_deferred
+                        .after(hold_piece)
+                        .after(restore_checkpoint)
+                        .after(restart_in_play)
+                        .after(restart_from_results),
+                    spawn_block.before(game_timer),
+                ).chain(),
+                position_transform,
+                update_gravity_text,
+                update_run_clock_text,
+                (update_height_meter, update_garbage_warning, update_score_text, render_next_piece, render_hold_piece),
+                (apply_ui_font, record_base_font_size, apply_ui_scale).chain(),
+                gameover,
+                update_combo_bar,
+                toggle_pause,
+                blink_pause_text,
+                audio_hotkeys,
+                apply_audio_volume,
+                switch_music,
+                fade_music,
+                open_options.run_if(in_state(GameState::Paused)),
+                (options_menu_input, update_options_text).chain().run_if(in_state(GameState::Options)),
+                restart_from_results.run_if(in_state(GameState::GameOver)),
+                dump_input_log,
+                (edit_board_on_click, export_board, export_state, toggle_slow_motion, toggle_coordinate_colors).run_if(in_state(GameState::Playing)),
+        ))
+        // ゲーム進行に関わるタイマー・アニメーションはポーズ中は止める
+        .add_systems(Update, (
+                game_timer,
+                block_horizontal_move,
+                block_rotate,
+                // ホールドで取り除いたピースを同じフレームに固定しないように、ホールドを反映してから落とす
+                // 一気に落として固定したピースを落下処理で二重に固定しないように、固定を反映してから落とす
+                (hold_piece, apply_deferred, block_vertical_move, apply_deferred, block_fall).chain(),
+                combo_timer,
+                tick_run_clock,
+                (gravity_hotkeys, adaptive_gravity).chain(),
+                warn_topout,
+                undo_lock,
+                (save_checkpoint, restore_checkpoint),
+                (rise_garbage, shrink_playfield),
+                play_lock_sound,
+                start_invisible_fade,
+                fade_out_blocks,
+                // ゴーストの表示とブロックの色もポーズ中は止めておく
+                (animate_spawn, animate_collapse, tint_blocks, render_ghost),
+                update_column_shadow,
+                show_move_hint,
+                restart_in_play,
+        ).run_if(in_state(GameState::Playing)))
+        .add_systems(OnEnter(GameState::Paused), spawn_pause_text)
+        .add_systems(OnExit(GameState::Paused), despawn_pause_text)
+        .add_systems(OnEnter(GameState::Options), spawn_options_text)
+        .add_systems(OnExit(GameState::Options), (despawn_options_text, apply_options))
+        .add_systems(First, record_input)
+        // 固定の反映（Commands）がすべて終わってから盤面と照らし合わせる
+        .add_systems(Last, (check_board_sync, limit_frame_rate).chain())
+        .add_systems(Last, save_on_exit)
+        .add_systems(OnEnter(GameState::GameOver), spawn_results_text)
+        .add_systems(OnExit(GameState::GameOver), despawn_results_text)
+    .run();
+}
+
+/**
+ * System: セットアップ
+ */
+pub(crate) fn setup(mut commands: Commands, asset_server: Res<AssetServer>, config: Res<GameConfig>) {
+    // 2D カメラ エンティティの作成
+    commands.spawn(Camera2dBundle::default());
+
+    // ウィンドウ全体の背景画像（ファイルが無ければ敷かずに、いつもの単色の背景にする）
+    let background_image = config
+        .background_image
+        .as_ref()
+        .and_then(|path| load_asset_if_exists(&asset_server, path));
+    if let Some(texture) = background_image {
+        commands.spawn(SpriteBundle {
+            sprite: Sprite {
+                custom_size: Some(Vec2::new(config.window_width(), config.window_height())),
+                ..Sprite::default()
+            },
+            texture,
+            transform: Transform::from_xyz(0.0, 0.0, BACKGROUND_IMAGE_Z),
+            ..SpriteBundle::default()
+        });
+    }
+
+    // マテリアルカラーを用意する
+    commands.insert_resource(Materials {
+        colors: vec![
+            Color::rgb(0.25, 0.9, 0.39),
+            Color::rgb(0.85, 0.25, 0.35),
+            Color::rgb(0.27, 0.59, 0.82),
+            Color::rgb(0.89, 0.9, 0.27),
+            Color::rgb(0.13, 0.89, 0.94),
+            Color::rgb(0.94, 0.55, 0.27),
+        ],
+    });
+
+    let offset = config.board_offset();
+    let board_size = config.board_size();
+
+    // コンボの残り時間バー（盤面の上端に表示）
+    commands
+    .spawn(SpriteBundle {
+        sprite: Sprite {
+            color: Color::rgb(0.95, 0.85, 0.3),
+            custom_size: Some(Vec2::new(0.0, 6.0)),
+            ..Sprite::default()
+        },
+        transform: Transform::from_xyz(offset.x, offset.y + board_size.y / 2.0 - 3.0, 2.0),
+        visibility: Visibility::Hidden,
+        ..SpriteBundle::default()
+    })
+    .insert(ComboBar);
+
+    // 盤面の枠（上下左右）
+    let half_width = board_size.x / 2.0 + BORDER_WIDTH / 2.0;
+    let half_height = board_size.y / 2.0 + BORDER_WIDTH / 2.0;
+    let horizontal = Vec2::new(board_size.x + BORDER_WIDTH * 2.0, BORDER_WIDTH);
+    let vertical = Vec2::new(BORDER_WIDTH, board_size.y + BORDER_WIDTH * 2.0);
+    [
+        (Vec2::new(0.0, half_height), horizontal),
+        (Vec2::new(0.0, -half_height), horizontal),
+        (Vec2::new(-half_width, 0.0), vertical),
+        (Vec2::new(half_width, 0.0), vertical),
+    ]
+    .iter()
+    .for_each(|(center, size)| {
+        commands
+        .spawn(SpriteBundle {
+            sprite: Sprite {
+                color: BORDER_COLOR,
+                custom_size: Some(*size),
+                ..Sprite::default()
+            },
+            transform: Transform::from_xyz(offset.x + center.x, offset.y + center.y, BORDER_Z),
+            ..SpriteBundle::default()
+        })
+        .insert(BoardBorder);
+    });
+
+    // 積み上がりの高さのゲージ（盤面の右の枠の外、ブロックが積み上がる側の端から伸ばす）
+    let meter_anchor = if config.board_up() > 0.0 {
+        bevy::sprite::Anchor::BottomCenter
+    } else {
+        bevy::sprite::Anchor::TopCenter
+    };
+    commands
+    .spawn(SpriteBundle {
+        sprite: Sprite {
+            anchor: meter_anchor,
+            custom_size: Some(Vec2::new(HEIGHT_METER_WIDTH, 0.0)),
+            ..Sprite::default()
+        },
+        transform: Transform::from_xyz(
+            offset.x + half_width + BORDER_WIDTH / 2.0 + HEIGHT_METER_GAP + HEIGHT_METER_WIDTH / 2.0,
+            offset.y - board_size.y / 2.0 * config.board_up(),
+            BORDER_Z,
+        ),
+        visibility: if config.height_meter { Visibility::Visible } else { Visibility::Hidden },
+        ..SpriteBundle::default()
+    })
+    .insert(HeightMeter);
+
+    // おじゃまブロックの予告（盤面の左端の下から、せり上がってくる行数の分だけ伸ばす）
+    let bottom_left = Vec2::new(offset.x - board_size.x / 2.0, offset.y - board_size.y / 2.0);
+    commands
+    .spawn(SpriteBundle {
+        sprite: Sprite {
+            anchor: bevy::sprite::Anchor::BottomCenter,
+            custom_size: Some(Vec2::new(GARBAGE_WARNING_WIDTH, config.cell_size().y * GARBAGE_LINES_PER_RISE as f32)),
+            ..Sprite::default()
+        },
+        transform: Transform::from_xyz(bottom_left.x + GARBAGE_WARNING_WIDTH / 2.0, bottom_left.y, BLOCK_Z + 0.5),
+        visibility: Visibility::Hidden,
+        ..SpriteBundle::default()
+    })
+    .insert(GarbageWarning);
+    commands
+    .spawn(Text2dBundle {
+        text: Text::from_section(
+            "",
+            TextStyle {
+                font_size: 18.0,
+                color: DANGER_COLOR,
+                ..TextStyle::default()
+            },
+        ),
+        text_anchor: bevy::sprite::Anchor::BottomLeft,
+        transform: Transform::from_xyz(
+            bottom_left.x + GARBAGE_WARNING_WIDTH + 4.0,
+            bottom_left.y + config.cell_size().y * GARBAGE_LINES_PER_RISE as f32 + 4.0,
+            10.0,
+        ),
+        ..Text2dBundle::default()
+    })
+    .insert(GarbageWarningText);
+
+    // 盤面の背景（市松模様の場合はマスごとに色を変えて、ブロックより後ろに置く）
+    if config.board_background == BoardBackground::Checkerboard {
+        for y in 0..Y_LENGTH as i32 {
+            for x in 0..X_LENGTH as i32 {
+                let center = board_to_world(x, y, &config);
+                commands
+                .spawn(SpriteBundle {
+                    sprite: Sprite {
+                        color: CHECKER_COLORS[((x + y) % 2) as usize],
+                        custom_size: Some(config.cell_size()),
+                        ..Sprite::default()
+                    },
+                    transform: Transform::from_xyz(center.x, center.y, BACKGROUND_Z),
+                    ..SpriteBundle::default()
+                })
+                .insert(BackgroundCell);
+            }
+        }
+    }
+
+    // 列の影（列ごとに１つ用意しておき、表示する時に高さを合わせる）
+    for x in 0..X_LENGTH as i32 {
+        commands
+        .spawn(SpriteBundle {
+            sprite: Sprite {
+                color: SHADOW_COLOR,
+                ..Sprite::default()
+            },
+            visibility: Visibility::Hidden,
+            ..SpriteBundle::default()
+        })
+        .insert(ColumnShadow(x));
+    }
+
+    // おすすめの置き場所（H キーを押している間だけ表示する）
+    for _ in 0..4 {
+        commands
+        .spawn(SpriteBundle {
+            sprite: Sprite {
+                color: HINT_COLOR,
+                custom_size: Some(config.cell_size()),
+                ..Sprite::default()
+            },
+            visibility: Visibility::Hidden,
+            ..SpriteBundle::default()
+        })
+        .insert(HintCell);
+    }
+
+    // 落下速度の表示（盤面の左上）
+    commands
+    .spawn(Text2dBundle {
+        text: Text::from_section(
+            "",
+            TextStyle {
+                font_size: 18.0,
+                color: Color::WHITE,
+                ..TextStyle::default()
+            },
+        ),
+        text_anchor: bevy::sprite::Anchor::TopLeft,
+        transform: Transform::from_xyz(
+            offset.x - board_size.x / 2.0 + 8.0,
+            offset.y + board_size.y / 2.0 - 12.0,
+            10.0,
+        ),
+        ..Text2dBundle::default()
+    })
+    .insert(GravityText);
+
+    // スローモーション中の表示（盤面の右上）
+    commands
+    .spawn(Text2dBundle {
+        text: Text::from_section(
+            "",
+            TextStyle {
+                font_size: 18.0,
+                color: Color::YELLOW,
+                ..TextStyle::default()
+            },
+        ),
+        text_anchor: bevy::sprite::Anchor::TopRight,
+        transform: Transform::from_xyz(
+            offset.x + board_size.x / 2.0 - 8.0,
+            offset.y + board_size.y / 2.0 - 12.0,
+            10.0,
+        ),
+        ..Text2dBundle::default()
+    })
+    .insert(SlowMotionText);
+
+    // プレイ時間の表示（盤面の上端の中央）
+    commands
+    .spawn(Text2dBundle {
+        text: Text::from_section(
+            "",
+            TextStyle {
+                font_size: 18.0,
+                color: Color::WHITE,
+                ..TextStyle::default()
+            },
+        ),
+        text_anchor: bevy::sprite::Anchor::TopCenter,
+        transform: Transform::from_xyz(offset.x, offset.y + board_size.y / 2.0 - 12.0, 10.0),
+        ..Text2dBundle::default()
+    })
+    .insert(RunClockText);
+}
+
+/**
+ * assets フォルダにファイルがあるか（無ければ警告を出す）
+ * 無いファイルを読み込もうとすると、何も表示されない・鳴らないまま気付けないので、読み込む前に確かめる
+ */
+pub(crate) fn asset_exists(path: &str) -> bool {
+    let exists = std::path::Path::new(ASSETS_DIR).join(path).is_file();
+    if !exists {
+        eprintln!("assets: not found: {}/{}", ASSETS_DIR, path);
+    }
+    exists
+}
+
+/**
+ * assets フォルダにファイルがあれば読み込む（無ければ警告を出して None）
+ */
+pub(crate) fn load_asset_if_exists<A: bevy::asset::Asset>(asset_server: &AssetServer, path: &str) -> Option<Handle<A>> {
+    asset_exists(path).then(|| asset_server.load(path.to_string()))
+}
+
+/**
+ * 表示する言語の文字列の読み込み
+ * 1行に `文字列の ID = 文字列` を書く（`#` で始まる行は無視する）。`font = パス` でその言語で使うフォントを指定できる
+ * ファイルが無い・読めない時や、ファイルに無い ID は英語で表示する
+ */
+pub(crate) fn load_ui_strings(language: &str) -> UiStrings {
+    let mut ui_strings = UiStrings {
+        strings: DEFAULT_UI_STRINGS.iter().map(|(id, text)| (id.to_string(), text.to_string())).collect(),
+        font: None,
+    };
+    if language == "en" {
+        return ui_strings;
+    }
+
+    let path = format!("{}/{}.txt", LANG_DIR, language);
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("lang: {}: {}", path, e);
+            return ui_strings;
+        }
+    };
+
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((id, value)) = line.split_once('=') else {
+            eprintln!("lang: invalid line: {}", line);
+            continue;
+        };
+        let (id, value) = (id.trim(), value.trim());
+
+        if id == "font" {
+            // 見つからなければ標準のフォントのまま（日本語などは表示できない）
+            if asset_exists(value) {
+                ui_strings.font = Some(value.to_string());
+            }
+        } else if ui_strings.strings.contains_key(id) {
+            ui_strings.strings.insert(id.to_string(), value.to_string());
+        } else {
+            eprintln!("lang: unknown id: {}", id);
+        }
+    }
+
+    ui_strings
+}
+
+/**
+ * 設定されたパズル盤面ファイルを読み込む（設定されていなければ None）
+ * 読み込めない場合は "puzzle:" で始まるメッセージを返す
+ */
+pub(crate) fn load_puzzle(config: &GameConfig) -> Result<Option<Vec<Vec<Option<Color>>>>, String> {
+    config
+        .puzzle_file
+        .as_ref()
+        .map(|path| load_initial_board(path).map_err(|message| format!("puzzle: {}", message)))
+        .transpose()
+}
+
+/**
+ * パズル盤面ファイルの読み込み
+ * 1行 X_LENGTH 文字（'#': 灰色のブロック, PUZZLE_COLORS の文字: その色のブロック, '.': 空）を上の行から順に Y_LENGTH 行並べる
+ */
+pub(crate) fn load_initial_board(path: &str) -> Result<Vec<Vec<Option<Color>>>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let rows: Vec<&str> = text
+        .lines()
+        .map(|line| line.trim_end())
+        .filter(|line| !line.is_empty())
+        .collect();
+
+    // 盤面サイズと一致するか検証
+    if rows.len() != Y_LENGTH as usize {
+        return Err(format!("{}: expected {} rows, found {}", path, Y_LENGTH, rows.len()));
+    }
+
+    let mut layout = vec![vec![None; X_LENGTH as usize]; Y_LENGTH as usize];
+    for (i, row) in rows.iter().enumerate() {
+        if row.chars().count() != X_LENGTH as usize {
+            return Err(format!("{}: row {} must have {} cells", path, i + 1, X_LENGTH));
+        }
+
+        // ファイルは上の行から、盤面は y = 0 が一番下
+        let y = Y_LENGTH as usize - 1 - i;
+        for (x, cell) in row.chars().enumerate() {
+            layout[y][x] = match cell {
+                '#' => Some(PUZZLE_BLOCK_COLOR),
+                '.' => None,
+                _ => match PUZZLE_COLORS.iter().find(|(c, _)| *c == cell) {
+                    Some((_, color)) => Some(*color),
+                    None => return Err(format!("{}: invalid cell '{}' in row {}", path, cell, i + 1)),
+                },
+            };
+        }
+    }
+
+    Ok(layout)
+}
+
+/**
+ * 盤面を load_initial_board で読み込める形式の文字列にする（上の行から書き出す）
+ */
+pub(crate) fn format_initial_board(game_board: &GameBoard) -> String {
+    (0..Y_LENGTH as i32)
+        .rev()
+        .map(|y| {
+            let row: String = (0..X_LENGTH as i32)
+                .map(|x| if game_board.get(x, y) == Some(true) { '#' } else { '.' })
+                .collect();
+            row + "\n"
+        })
+        .collect()
+}
+
+/**
+ * 初期盤面をゲーム盤面に配置し、固定ブロックを生成する
+ */
+pub(crate) fn fill_initial_board(
+    commands: &mut Commands,
+    block_texture: &BlockTexture,
+    game_board: &mut GameBoard,
+    layout: &[Vec<Option<Color>>],
+    config: &GameConfig,
+) {
+    for (y, row) in layout.iter().enumerate() {
+        for (x, cell) in row.iter().enumerate() {
+            let Some(color) = cell else {
+                continue;
+            };
+
+            game_board.set(x as i32, y as i32, true);
+            spawn_block_sprite(commands, block_texture, *color, config)
+            .insert(Position {
+                x: x as i32,
+                y: y as i32,
+            })
+            .insert(Fix);
+        }
+    }
+}
+
+/**
+ * ブロック１マス分のスプライトを生成する
+ * block_texture が設定されていれば画像をブロックの色で染めて使う
+ * 縁取りは一回り大きい暗いスプライトを子として後ろに置き、ブロックの移動に追従させる
+ */
+pub(crate) fn spawn_block_sprite<'w, 's, 'a>(
+    commands: &'a mut Commands<'w, 's>,
+    block_texture: &BlockTexture,
+    color: Color,
+    config: &GameConfig,
+) -> bevy::ecs::system::EntityCommands<'w, 's, 'a> {
+    let mut fill_color = color;
+    fill_color.set_a(config.block_opacity);
+
+    let mut block = commands.spawn(SpriteBundle {
+        sprite: Sprite {
+            color: fill_color,
+            ..Sprite::default()
+        },
+        texture: block_texture.0.clone(),
+        ..SpriteBundle::default()
+    });
+    block.insert(BlockColor(color));
+
+    if config.block_outline {
+        block.with_children(|parent| {
+            parent.spawn(SpriteBundle {
+                sprite: Sprite {
+                    color: Color::rgba(0.05, 0.05, 0.05, config.block_opacity),
+                    custom_size: Some(config.cell_size()),
+                    ..Sprite::default()
+                },
+                transform: Transform::from_xyz(0.0, 0.0, -0.5),
+                ..SpriteBundle::default()
+            });
+        });
+    }
+
+    block
+}
+
+/**
+ * System: 次のブロックの決定
+ */
+pub(crate) fn next_block(block_patterns: &Vec<BlockPattern>, piece_bag: &mut PieceBag, rng: &mut StdRng) -> BlockPattern {
+    // 袋が空になったら、すべての形を１つずつ入れて混ぜ直す（同じ形が続きすぎたり、長く出なかったりしない）
+    if piece_bag.queue.is_empty() {
+        let mut indices: Vec<usize> = (0..block_patterns.len()).collect();
+        indices.shuffle(rng);
+        piece_bag.queue.extend(indices);
+    }
+    let pattern_index = piece_bag.queue.pop_front().unwrap_or_default();
+
+    block_patterns[pattern_index].clone()
+}
+
+/**
+ * ピースの形を作る（回転の中心は半マス単位で指定する）
+ */
+pub(crate) fn block_pattern(cells: Vec<(i32, i32)>, (x2, y2): (i32, i32)) -> BlockPattern {
+    BlockPattern {
+        cells,
+        center: RotationCenter { x2, y2 },
+    }
+}
+
+/**
+ * ７種類のピースの形
+ */
+pub(crate) fn standard_block_patterns() -> Vec<BlockPattern> {
+    vec![
+        block_pattern(vec![(0, 0), (0, -1), (0, 1), (0, 2)], (1, 1)),  // I
+        block_pattern(vec![(0, 0), (0, -1), (0, 1), (-1, 1)], (0, 0)), // L
+        block_pattern(vec![(0, 0), (0, -1), (0, 1), (1, 1)], (0, 0)),  // 逆L
+        block_pattern(vec![(0, 0), (0, -1), (1, 0), (1, 1)], (0, 0)),  // Z
+        block_pattern(vec![(0, 0), (1, 0), (0, 1), (1, -1)], (0, 0)),  // 逆Z
+        block_pattern(vec![(0, 0), (0, 1), (1, 0), (1, 1)], (1, 1)),   // 四角
+        block_pattern(vec![(0, 0), (-1, 0), (1, 0), (0, 1)], (0, 0)),  // T
+    ]
+}
+
+/**
+ * System: ブロックの色の決定
+ */
+pub(crate) fn next_color(colors: &Vec<Color>, rng: &mut StdRng) -> Color {
+    let mut color_index: usize = rng.gen();
+    color_index %= colors.len();
+
+    colors[color_index]
+}
+
+/**
+ * ピースを生成位置に置いた時の各ブロックの（絶対座標, 相対座標）
+ * ピースの生成と、生成後の形を見せる表示とで同じ変換を使うこと
+ */
+pub(crate) fn spawn_cells(pattern: &[(i32, i32)], walls: &PlayfieldWalls) -> Vec<PieceCell> {
+    // ブロックの初期位置（壁で狭まっている時は残っている範囲の中央）
+    let initial_x = walls.spawn_x();
+    let initial_y = Y_LENGTH as i32;
+
+    pattern
+        .iter()
+        .map(|(r_x, r_y)| ((initial_x + r_x, initial_y + r_y), (*r_x, *r_y)))
+        .collect()
+}
+
+/**
+ * 出てきた時の向きの形が cells のピースの形（BlockPatterns に無ければ None）
+ */
+pub(crate) fn find_pattern(block_patterns: &[BlockPattern], cells: &[(i32, i32)]) -> Option<BlockPattern> {
+    block_patterns.iter().find(|pattern| pattern.cells == cells).cloned()
+}
+
+/**
+ * 操作中のピースの相対座標から、出てきた時の向きの形を探す（回していても同じ形と分かるように４つの向きを試す）
+ */
+pub(crate) fn spawn_orientation(
+    block_patterns: &[BlockPattern],
+    relative: &[(i32, i32)],
+    center: &RotationCenter,
+) -> Option<Vec<(i32, i32)>> {
+    let mut relative = relative.to_vec();
+    relative.sort();
+
+    block_patterns
+        .iter()
+        .filter(|pattern| pattern.center.x2 == center.x2 && pattern.center.y2 == center.y2)
+        .find(|pattern| {
+            let mut rotated = BlockPattern::clone(pattern);
+            (0..4).any(|_| {
+                let mut cells = rotated.cells.clone();
+                cells.sort();
+                rotated.cells = rotate_pattern(&rotated);
+                cells == relative
+            })
+        })
+        .map(|pattern| pattern.cells.clone())
+}
+
+/**
+ * System: Shift キーで操作中のピースをホールドする
+ * ホールドが空なら次のピースを、ホールドしていれば入れ替えてそのピースを出す（ピースを固定するまで１回だけ）
+ */
+pub(crate) fn hold_piece(
+    mut commands: Commands,
+    key_input: Res<Input<KeyCode>>,
+    block_patterns: Res<BlockPatterns>,
+    mut hold: ResMut<Hold>,
+    mut new_block_events: EventWriter<NewBlockEvent>,
+    free_query: Query<(Entity, &RelativePosition, &RotationCenter, &BlockColor), With<Free>>,
+) {
+    if !(key_input.just_pressed(KeyCode::ShiftLeft) || key_input.just_pressed(KeyCode::ShiftRight)) || !can_hold(&hold) {
+        return;
+    }
+
+    let Some((_, _, center, color)) = free_query.iter().next() else {
+        return;
+    };
+    let relative: Vec<(i32, i32)> = free_query.iter().map(|(_, r_pos, _, _)| (r_pos.rot_x, r_pos.rot_y)).collect();
+    let Some(pattern) = spawn_orientation(&block_patterns.0, &relative, center) else {
+        return;
+    };
+    let color = color.0;
+
+    free_query.iter().for_each(|(entity, _, _, _)| {
+        commands.entity(entity).despawn_recursive();
+    });
+
+    hold.swap_in = hold.pattern.take().zip(hold.color.take());
+    hold.pattern = Some(pattern);
+    hold.color = Some(color);
+    hold.used_this_turn = true;
+    hold.swap_requested = true;
+    new_block_events.send(NewBlockEvent);
+}
+
+/**
+ * 今のピースをホールドできるか
+ * 一度ホールドしたら、次のピースを生成する（spawn_block で used_this_turn を戻す）まで何度押しても入れ替えない
+ */
+pub(crate) fn can_hold(hold: &Hold) -> bool {
+    !hold.used_this_turn && !hold.swap_requested
+}
+
+/**
+ * ピースの形を回転の中心の周りに時計回りに 90 度回した相対座標
+ */
+pub(crate) fn rotate_pattern(pattern: &BlockPattern) -> Vec<(i32, i32)> {
+    pattern
+        .cells
+        .iter()
+        .map(|r_pos| rotate_relative(*r_pos, &pattern.center))
+        .collect()
+}
+
+/**
+ * 生成位置に置けるピースを何マス上にずらせば置けるか（置けなければ None）
+ * 生成する向きのまま、最大 SPAWN_NUDGE_LIMIT マスまで上にずらして試す
+ */
+pub(crate) fn spawn_nudge(game_board: &GameBoard, cells: &[PieceCell]) -> Option<i32> {
+    let positions: Vec<(i32, i32)> = cells.iter().map(|(pos, _)| *pos).collect();
+    (0..=SPAWN_NUDGE_LIMIT).find(|nudge| can_occupy(game_board, &offset_cells(&positions, 0, *nudge)))
+}
+
+/**
+ * 次に出すピースを決めるのに必要なリソース
+ */
+#[derive(SystemParam)]
+pub(crate) struct PieceSource<'w> {
+    block_patterns: Res<'w, BlockPatterns>,
+    materials: Res<'w, Materials>,
+    piece_bag: ResMut<'w, PieceBag>,
+    next_piece: ResMut<'w, NextPiece>,
+    hold: ResMut<'w, Hold>,
+    rng: ResMut<'w, GameRng>,
+    piece_counter: ResMut<'w, PieceCounter>,
+}
+
+/**
+ * ピースを生成するタイミングを決めるのに必要なリソース
+ */
+#[derive(SystemParam)]
+pub(crate) struct SpawnTiming<'w> {
+    time: Res<'w, Time>,
+    time_scale: Res<'w, TimeScale>,
+    state: Res<'w, State<GameState>>,
+    game_timer: ResMut<'w, GameTimer>,
+    spawn_delay: ResMut<'w, SpawnDelay>,
+}
+
+/**
+ * System: ブロックの生成
+ * デバッグ用に spawn_jitter_ms が設定されていれば、GameRng で決めた時間だけ待ってから生成する
+ * ライン消去で残ったブロックが下がる演出の間も、終わるまで待ってから生成する
+ */
+pub(crate) fn spawn_block(
+    mut commands: Commands,
+    // システムの引数の数の上限に収めるために、いくつかまとめている
+    (key_input, config): (Res<Input<KeyCode>>, Res<GameConfig>),
+    (block_texture, game_board, walls): (Res<BlockTexture>, Res<GameBoard>, Res<PlayfieldWalls>),
+    mut source: PieceSource,
+    mut timing: SpawnTiming,
+    (mut new_block_event_reader, mut gameover_events): (EventReader<NewBlockEvent>, EventWriter<GameOverEvent>),
+    block_query: Query<(&Position, Has<Free>, Has<CollapseAnimation>)>,
+) {
+    // 同じフレームに複数届いても生成するのは１つだけにして、残りを次のフレームに持ち越さない
+    let requested = new_block_event_reader.read().count() > 0;
+
+    // 同じシードなら同じ待ち時間になるように、ピースと同じ乱数で決める
+    if requested {
+        let delay = if config.debug && config.spawn_jitter_ms > 0 {
+            source.rng.0.gen_range(0..=config.spawn_jitter_ms)
+        } else {
+            0
+        };
+        timing.spawn_delay.0 = Some(Timer::new(std::time::Duration::from_millis(delay), TimerMode::Once));
+    }
+    // 要求はポーズ中も受け取っておき、待ち時間はゲームの進行と同じ時間で進める（ポーズ中は生成しない）
+    let SpawnTiming { time, time_scale, state, game_timer, spawn_delay } = &mut timing;
+    let Some(timer) = &mut spawn_delay.0 else {
+        return;
+    };
+    if *state.get() != GameState::Playing {
+        return;
+    }
+    timer.tick(time.delta().mul_f32(time_scale.0));
+    if !timer.finished() || block_query.iter().any(|(_, _, collapsing)| collapsing) {
+        return;
+    }
+    spawn_delay.0 = None;
+
+    // 待っている間に元に戻す・チェックポイントでピースが戻っていれば、もう生成しない
+    if !requested && block_query.iter().any(|(_, free, _)| free) {
+        return;
+    }
+
+    // ホールドで入れ替えたピースはそのまま出す（次のピースが出るまでホールドは使えないまま）
+    let from_hold = std::mem::take(&mut source.hold.swap_requested);
+    source.hold.used_this_turn = from_hold;
+    let held = source
+        .hold
+        .swap_in
+        .take()
+        .and_then(|(cells, color)| find_pattern(&source.block_patterns.0, &cells).map(|pattern| (pattern, color)));
+
+    // 次のピースとして見せていたものを出し、その次のピースを決めておく
+    let (new_block, new_color) = match held {
+        Some(piece) => piece,
+        None => {
+            let PieceSource { block_patterns, materials, piece_bag, next_piece, rng, .. } = &mut source;
+            let mut draw = || {
+                let block = next_block(&block_patterns.0, piece_bag, &mut rng.0);
+                (block, next_color(&materials.colors, &mut rng.0))
+            };
+            let piece = next_piece.0.take().unwrap_or_else(&mut draw);
+            next_piece.0 = Some(draw());
+            piece
+        }
+    };
+    let mut cells = spawn_cells(&new_block.cells, &walls);
+
+    // ↑キーを押したまま出てきたら、回した向きで出す（回した向きで置けなければそのまま）
+    if config.initial_rotation && key_input.pressed(KeyCode::Up) {
+        let rotated = spawn_cells(&rotate_pattern(&new_block), &walls);
+        if spawn_nudge(&game_board, &rotated).is_some() {
+            cells = rotated;
+        }
+    }
+
+    // ゲームオーバー判定
+    // 生成位置が塞がっていれば上にずらしてみて、それでも置けなければゲームオーバー
+    let Some(nudge) = spawn_nudge(&game_board, &cells) else {
+        // ブロックを生成せずにゲームオーバーイベントを通知
+        gameover_events.send(GameOverEvent);
+        println!("Game Over");
+        return;
+    };
+
+    let cells: Vec<PieceCell> = cells.iter().map(|((x, y), r_pos)| ((*x, y + nudge), *r_pos)).collect();
+
+    // 生成位置の計算が壊れていたら、ブロックを重ねて置く前に開発中に気付けるようにする
+    // 盤面だけでなく、盤面とずれて残っているブロックとも重ならないことを確かめる
+    debug_assert!(
+        cells.iter().all(|((x, y), _)| {
+            game_board.get(*x, *y) != Some(true) && !block_query.iter().any(|(pos, _, _)| (pos.x, pos.y) == (*x, *y))
+        }),
+        "spawn_block: new piece overlaps an occupied cell: {:?}",
+        cells,
+    );
+
+    let piece_id = PieceId(source.piece_counter.0);
+    source.piece_counter.0 = source.piece_counter.0.wrapping_add(1);
+
+    cells.iter().for_each(|((pos_x, pos_y), (r_x, r_y))| {
+        // ブロック エンティティの作成
+        let mut block = spawn_block_sprite(&mut commands, &block_texture, new_color, &config);
+        if !config.reduced_motion {
+            block
+            .insert(Transform::from_scale(Vec3::new(SPAWN_ANIMATION_START_SCALE, SPAWN_ANIMATION_START_SCALE, 1.0)))
+            .insert(SpawnAnimation(Timer::from_seconds(SPAWN_ANIMATION_SECS, TimerMode::Once)));
+        }
+        block
+        .insert(Position {
+            // ブロックの初期座標
+            // x: 0 ～ 9
+            // y: 0 ～ 17
+            x: *pos_x,
+            y: *pos_y,
+        })
+        .insert(RelativePosition {
+            rot_x: *r_x,
+            rot_y: *r_y,
+        })
+        .insert(new_block.center)
+        .insert(piece_id)
+        .insert(Free);
+    });
+
+    // 生成したピースは一番上で落下間隔１回分は留まる
+    game_timer.0.reset();
+}
+
+/**
+ * 盤面の座標（左下が (0, 0)、上向きが +y）をマスの中心のワールド座標に変換する
+ * 盤面の中心は GameConfig::board_offset、x はマスの幅、y はマスの高さで掛ける
+ * inverted モードでは y = 0 が一番上になる（GameConfig::board_up）
+ */
+pub(crate) fn board_to_world(x: i32, y: i32, config: &GameConfig) -> Vec2 {
+    let cell = config.cell_size();
+    let from_center = Vec2::new(x as f32 + 0.5, y as f32 + 0.5) * cell - config.board_size() / 2.0;
+
+    config.board_offset() + Vec2::new(from_center.x, from_center.y * config.board_up())
+}
+
+/**
+ * ワールド座標をそれを含むマスの盤面の座標に変換する（board_to_world の逆）
+ * 盤面の外の座標も範囲チェックせずにそのまま変換する
+ */
+pub(crate) fn world_to_board(px: f32, py: f32, config: &GameConfig) -> (i32, i32) {
+    let cell = config.cell_size();
+    let from_center = Vec2::new(px, py) - config.board_offset();
+    let from_corner = Vec2::new(from_center.x, from_center.y * config.board_up()) + config.board_size() / 2.0;
+
+    (
+        (from_corner.x / cell.x).floor() as i32,
+        (from_corner.y / cell.y).floor() as i32,
+    )
+}
+
+/**
+ * System: ブロックの移動
+ */
+pub(crate) fn position_transform(
+    config: Res<GameConfig>,
+    mut position_query: Query<(&Position, &mut Transform, &mut Sprite, Option<&CollapseAnimation>)>,
+) {
+    // 縁取りを表示する場合は塗りつぶしを縁取りの分だけ小さくする
+    let inset = if config.block_outline { (OUTLINE_WIDTH * 2) as f32 } else { 0.0 };
+    let size = config.cell_size() - Vec2::splat(inset);
+
+    position_query
+        .iter_mut()
+        .for_each(|(pos, mut transform, mut sprite, collapse)| {
+            transform.translation = board_to_world(pos.x, pos.y, &config).extend(BLOCK_Z);
+            // ライン消去で下がっている途中なら、残りの分だけ上に表示する
+            if let Some(collapse) = collapse {
+                transform.translation.y += collapse.rows as f32 * config.cell_size().y * config.board_up() * collapse.timer.percent_left();
+            }
+            sprite.custom_size = Some(size)
+        });
+}
+
+/**
+ * System: タイマーを進める
+ */
+pub(crate) fn game_timer(
+    time: Res<Time>,
+    time_scale: Res<TimeScale>,
+    mut game_timer: ResMut<GameTimer>,
+    mut input_timer: ResMut<InputTimer>,
+    mut rotate_cooldown: ResMut<RotateCooldown>,
+    mut gravity_tick_events: EventWriter<GravityTickEvent>,
+) {
+    let delta = time.delta().mul_f32(time_scale.0);
+    game_timer.0.tick(delta);
+    input_timer.0.tick(delta);
+    rotate_cooldown.0.tick(delta);
+
+    // 落下のタイミングを通知する
+    // 落下間隔がフレームより短い時は、１フレームで過ぎた回数だけ通知する
+    for _ in 0..game_timer.0.times_finished_this_tick() {
+        gravity_tick_events.send(GravityTickEvent);
+    }
+}
+
+/**
+ * System: ブロックの落下
+ */
+pub(crate) fn block_fall(
+    mut commands: Commands,
+    mut gravity_tick_reader: EventReader<GravityTickEvent>,
+    mut block_query: Query<(Entity, &mut Position, &Free)>,
+    piece_query: Query<&PieceId, With<Free>>,
+    mut lock: PieceLock,
+) {
+    // １フレームで何マス分落ちる場合も、すり抜けないように１マスずつ調べて落とす
+    let steps = gravity_tick_reader.read().count();
+    for _ in 0..steps {
+        // ブロックがそれ以上落下できないかを調べる（yが0、または一つ下にブロックがすでに存在する）
+        let cells = active_piece_cells(&block_query);
+        let cannot_fall = !can_occupy(&lock.game_board, &offset_cells(&cells, 0, -1));
+
+        if cannot_fall {
+            // 落下できない
+            lock_piece(&mut commands, &block_query, piece_query.iter().next().copied(), &mut lock);
+            return;
+        }
+
+        // 落下
+        block_query.iter_mut().for_each(|(_, mut pos, _)| {
+            pos.y -= 1;
+        });
+    }
+}
+
+/**
+ * ピースを固定するのに必要なリソース
+ */
+#[derive(SystemParam)]
+pub(crate) struct PieceLock<'w> {
+    game_board: ResMut<'w, GameBoard>,
+    config: Res<'w, GameConfig>,
+    lock_history: ResMut<'w, LockHistory>,
+    new_block_events: EventWriter<'w, NewBlockEvent>,
+    gameover_events: EventWriter<'w, GameOverEvent>,
+}
+
+/**
+ * 操作中のピースをその場で固定して、次のピースを要求する
+ * ロックアウトのルールで盤面より上に固定した時は代わりにゲームオーバーを通知する
+ */
+pub(crate) fn lock_piece(
+    commands: &mut Commands,
+    block_query: &Query<(Entity, &mut Position, &Free)>,
+    piece_id: Option<PieceId>,
+    lock: &mut PieceLock,
+) {
+    block_query.iter().for_each(|(entity, pos, _)| {
+        commands.entity(entity).remove::<Free>();
+        commands.entity(entity).insert(Fix);
+        lock.game_board.set(pos.x, pos.y, true);
+    });
+
+    // 練習モードでは元に戻せるように記録しておく
+    if lock.config.mode == GameMode::Practice {
+        if let Some(piece_id) = piece_id {
+            lock.lock_history.0.push(LockedPiece {
+                piece_id,
+                cells: active_piece_cells(block_query),
+            });
+            if lock.lock_history.0.len() > UNDO_LIMIT {
+                lock.lock_history.0.remove(0);
+            }
+        }
+    }
+
+    // ピースが丸ごと見えている範囲より上で固定されたらゲームオーバー
+    if lock.config.topout_rule == TopOutRule::LockOut && is_lock_out(&active_piece_cells(block_query)) {
+        lock.gameover_events.send(GameOverEvent);
+        println!("Game Over");
+        return;
+    }
+
+    // 新しくブロックを生成するためのイベントを通知
+    lock.new_block_events.send(NewBlockEvent);
+}
+
+/**
+ * 固定したピースのブロックがすべて見えている範囲より上（y >= Y_LENGTH）にあるか
+ */
+pub(crate) fn is_lock_out(cells: &[(i32, i32)]) -> bool {
+    !cells.is_empty() && cells.iter().all(|(_, y)| *y >= Y_LENGTH as i32)
+}
+
+/**
+ * System: ブロックの水平移動
+ */
+pub(crate) fn block_horizontal_move(
+    key_input: Res<Input<KeyCode>>,
+    timer: ResMut<InputTimer>,
+    game_board: ResMut<GameBoard>,
+    mut free_block_query: Query<(Entity, &mut Position, &Free)>,
+) {
+    if !timer.0.finished() {
+        return;
+    }
+
+    // 左右を同時に押している時は打ち消し合って動かない（壁際で片方だけ動くこともない）
+    let dx = key_input.pressed(KeyCode::Right) as i32 - key_input.pressed(KeyCode::Left) as i32;
+    if dx == 0 {
+        return;
+    }
+
+    // 移動できるか判定
+    let cells = active_piece_cells(&free_block_query);
+    if can_occupy(&game_board, &offset_cells(&cells, dx, 0)) {
+        free_block_query.iter_mut().for_each(|(_, mut pos, _)| {
+            pos.x += dx;
+        });
+    }
+}
+
+/**
+ * System: ブロックの下移動
+ */
+pub(crate) fn block_vertical_move(
+    mut commands: Commands,
+    key_input: Res<Input<KeyCode>>,
+    timer: Res<InputTimer>,
+    mut free_block_query: Query<(Entity, &mut Position, &Free)>,
+    piece_query: Query<&PieceId, With<Free>>,
+    mut lock: PieceLock,
+) {
+    if lock.config.effective_soft_drop_style() == SoftDropStyle::Gradual {
+        // 押している間、左右移動と同じ間隔で１マスずつ落とす
+        if !timer.0.finished() || !key_input.pressed(KeyCode::Down) {
+            return;
+        }
+
+        let cells = active_piece_cells(&free_block_query);
+        if drop_distance(&lock.game_board, &cells) == 0 {
+            return;
+        }
+        free_block_query.iter_mut().for_each(|(_, mut pos, _)| {
+            pos.y -= 1;
+        });
+        return;
+    }
+
+    if !key_input.just_pressed(KeyCode::Down) {
+        return;
+    }
+
+    // ブロックが衝突する位置を調べる
+    let cells = active_piece_cells(&free_block_query);
+    let down_height = drop_distance(&lock.game_board, &cells);
+
+    // ブロックが衝突しないギリギリの位置まで移動
+    free_block_query.iter_mut().for_each(|(_, mut pos, _)| {
+        pos.y -= down_height;
+    });
+
+    // その場で固定しない場合は次の落下タイミングで固定される
+    if lock.config.hard_drop_locks && !cells.is_empty() {
+        lock_piece(&mut commands, &free_block_query, piece_query.iter().next().copied(), &mut lock);
+    }
+}
+
+/**
+ * 操作中のピースを構成するブロックの座標を集める
+ */
+pub(crate) fn active_piece_cells(free_block_query: &Query<(Entity, &mut Position, &Free)>) -> Vec<(i32, i32)> {
+    free_block_query
+        .iter()
+        .map(|(_, pos, _)| (pos.x, pos.y))
+        .collect()
+}
+
+/**
+ * ブロックをすべて置けるか（壁と床の外、固定されたブロックのあるマスには置けない）
+ * 落下・左右移動・回転・生成の判定はすべてこれを使うこと
+ */
+pub(crate) fn can_occupy(game_board: &GameBoard, cells: &[(i32, i32)]) -> bool {
+    cells.iter().all(|(x, y)| game_board.is_vacant(*x, *y))
+}
+
+/**
+ * ブロックの座標をまとめてずらす
+ */
+pub(crate) fn offset_cells(cells: &[(i32, i32)], dx: i32, dy: i32) -> Vec<(i32, i32)> {
+    cells.iter().map(|(x, y)| (x + dx, y + dy)).collect()
+}
+
+/**
+ * ブロックを真下に落とせる段数を調べる
+ * 盤面より上（バッファ領域）のマスは空として扱い、y が負になる位置は床として扱う
+ */
+pub(crate) fn drop_distance(game_board: &GameBoard, cells: &[(i32, i32)]) -> i32 {
+    if cells.is_empty() {
+        return 0;
+    }
+
+    let mut down_height = 0;
+    loop {
+        let next_height = down_height + 1;
+        let collide = !can_occupy(game_board, &offset_cells(cells, 0, -next_height));
+
+        if collide {
+            return down_height;
+        }
+        down_height = next_height;
+    }
+}
+
+/**
+ * ピースの原点からの相対座標を回転の中心の周りに時計回りに 90 度回す
+ * 回転行列を使い、半マス単位で回転の中心からの距離を回してから、マス単位に戻す
+ */
+pub(crate) fn rotate_relative((rot_x, rot_y): (i32, i32), center: &RotationCenter) -> (i32, i32) {
+    // cos,-sin,sin,cos (-90)
+    let rot_matrix = [[0, 1], [-1, 0]];
+
+    let d_x = rot_x * 2 - center.x2;
+    let d_y = rot_y * 2 - center.y2;
+    (
+        (center.x2 + rot_matrix[0][0] * d_x + rot_matrix[0][1] * d_y) / 2,
+        (center.y2 + rot_matrix[1][0] * d_x + rot_matrix[1][1] * d_y) / 2,
+    )
+}
+
+/**
+ * System: ブロックの回転移動
+ */
+pub(crate) fn block_rotate(
+    key_input: Res<Input<KeyCode>>,
+    mut rotate_cooldown: ResMut<RotateCooldown>,
+    game_board: ResMut<GameBoard>,
+    mut free_block_query: Query<(Entity, &mut Position, &mut RelativePosition, &RotationCenter, &Free)>,
+) {
+    // キーリピートなどで続けて押されても rotate_interval_ms より速くは回さない
+    if !key_input.just_pressed(KeyCode::Up) || !rotate_cooldown.0.finished() {
+        return;
+    }
+
+    // ピースの原点はそのままで、相対座標だけを回す
+    fn calc_rotated_pos(pos: &Position, r_pos: &RelativePosition, center: &RotationCenter) -> ((i32, i32), (i32, i32)) {
+        let origin_pos_x = pos.x - r_pos.rot_x;
+        let origin_pos_y = pos.y - r_pos.rot_y;
+
+        let (new_r_pos_x, new_r_pos_y) = rotate_relative((r_pos.rot_x, r_pos.rot_y), center);
+        let new_pos_x = origin_pos_x + new_r_pos_x;
+        let new_pos_y = origin_pos_y + new_r_pos_y;
+
+        ((new_pos_x, new_pos_y), (new_r_pos_x, new_r_pos_y))
+    }
+
+    // 回転操作可能かどうか判定
+    let rotated: Vec<(i32, i32)> = free_block_query
+        .iter()
+        .map(|(_, pos, r_pos, center, _)| calc_rotated_pos(pos, r_pos, center).0)
+        .collect();
+    let rotable = can_occupy(&game_board, &rotated);
+
+    if !rotable {
+        return;
+    }
+    rotate_cooldown.0.reset();
+
+    // 相対座標と絶対座標を更新
+    free_block_query
+        .iter_mut()
+        .for_each(|(_, mut pos, mut r_pos, center, _)| {
+            let ((new_pos_x, new_pos_y), (new_r_pos_x, new_r_pos_y)) =
+                calc_rotated_pos(&pos, &r_pos, center);
+            r_pos.rot_x = new_r_pos_x;
+            r_pos.rot_y = new_r_pos_y;
+
+            pos.x = new_pos_x;
+            pos.y = new_pos_y;
+        });
+}
+
+/**
+ * 同時に消した行数に対する得点（４行より多くても４行と同じ）
+ */
+pub(crate) fn line_clear_score(lines: usize) -> u32 {
+    LINE_CLEAR_SCORES[lines.min(LINE_CLEAR_SCORES.len() - 1)]
+}
+
+/**
+ * System: ブロックの削除
+ */
+pub(crate) fn delete_line(
+    mut commands: Commands,
+    mut gravity_tick_reader: EventReader<GravityTickEvent>,
+    mut game_board: ResMut<GameBoard>,
+    mut fixed_block_query: Query<(Entity, &mut Position, &Fix)>,
+    (mut combo, mut combo_timer, mut score): (ResMut<Combo>, ResMut<ComboTimer>, ResMut<Score>),
+    config: Res<GameConfig>,
+) {
+    if gravity_tick_reader.read().count() == 0 {
+        return;
+    }
+
+    // 消去対象のブロック行をHashSetに入れていく
+    // 見えている範囲より上のバッファ領域で揃った行も消す
+    let mut delete_line_set = std::collections::HashSet::new();
+    for y in 0..game_board.height() {
+        let delete_current_line = (0..X_LENGTH as i32).all(|x| game_board.get(x, y) == Some(true));
+
+        if delete_current_line {
+            delete_line_set.insert(y);
+        }
+    }
+
+    if delete_line_set.is_empty() {
+        return;
+    }
+
+    // ラインを消去したらコンボを継続し、制限時間を戻す
+    combo.0 += 1;
+    combo_timer.0.reset();
+
+    // 同時に消した行数で得点を決める（４行同時は１行ずつ４回より高い）
+    score.0 = score.0.saturating_add(line_clear_score(delete_line_set.len()));
+
+    // 消去対象ブロック行に含まれるブロックをゲーム盤面から削除する
+    fixed_block_query.iter().for_each(|(_, pos, _)| {
+        if delete_line_set.contains(&pos.y) {
+            game_board.set(pos.x, pos.y, false);
+        }
+    });
+
+    if config.line_clear_gravity == LineClearGravity::Sticky {
+        // 消去の対象のブロックをゲームから取り除き、残ったブロックを集める
+        let mut remaining = vec![];
+        fixed_block_query.iter().for_each(|(entity, pos, _)| {
+            if delete_line_set.contains(&pos.y) {
+                commands.entity(entity).despawn_recursive();
+            } else {
+                remaining.push((entity, pos.x, pos.y));
+            }
+        });
+
+        // 塊ごとに落とした後の新しいY座標を適用
+        let cells: Vec<(i32, i32)> = remaining.iter().map(|(_, x, y)| (*x, *y)).collect();
+        let new_ys = sticky_collapse(&cells);
+        cells.iter().for_each(|(x, y)| {
+            game_board.set(*x, *y, false);
+        });
+        remaining.iter().zip(new_ys.iter()).for_each(|((entity, x, y), new_y)| {
+            if let Ok((_, mut pos, _)) = fixed_block_query.get_mut(*entity) {
+                pos.y = *new_y;
+            }
+            game_board.set(*x, *new_y, true);
+            start_collapse_animation(&mut commands, *entity, y - new_y, &config);
+        });
+        // 落ちた先で揃った行は次の落下タイミングで消える
+        return;
+    }
+
+    // 各Y座標について、ブロック消去適用後の新しいY座標を調べる
+    let new_y = |y: i32| y - delete_line_set.iter().filter(|line| y > **line).count() as i32;
+
+    // 残ったブロックの元のマスをすべて空けてから、下がった先のマスを埋める
+    // （１つずつ動かすと、同じ列の下のブロックが後から動いた時に上のブロックが入ったマスを空けてしまう）
+    let mut moved = vec![];
+    fixed_block_query.iter_mut().for_each(|(entity, mut pos, _)| {
+        if delete_line_set.contains(&pos.y) {
+            // 消去の対象のブロックをゲームから取り除く
+            commands.entity(entity).despawn_recursive();
+        } else {
+            // ブロック消去適用後の新しいY座標を適用
+            game_board.set(pos.x, pos.y, false);
+            let old_y = pos.y;
+            pos.y = new_y(pos.y);
+            moved.push((pos.x, pos.y));
+            start_collapse_animation(&mut commands, entity, old_y - pos.y, &config);
+        }
+    });
+    moved.iter().for_each(|(x, y)| {
+        game_board.set(*x, *y, true);
+    });
+}
+
+/**
+ * ライン消去で rows 行下がったブロックに、元の高さから滑らせる演出を付ける
+ * reduced_motion の時は演出せずにすぐ下がった位置に表示する
+ */
+pub(crate) fn start_collapse_animation(commands: &mut Commands, entity: Entity, rows: i32, config: &GameConfig) {
+    if rows <= 0 || config.reduced_motion {
+        return;
+    }
+
+    commands.entity(entity).insert(CollapseAnimation {
+        rows,
+        timer: Timer::from_seconds(COLLAPSE_ANIMATION_SECS, TimerMode::Once),
+    });
+}
+
+/**
+ * System: ライン消去で下がったブロックの演出を進める
+ */
+pub(crate) fn animate_collapse(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut collapse_query: Query<(Entity, &mut CollapseAnimation)>,
+) {
+    collapse_query.iter_mut().for_each(|(entity, mut collapse)| {
+        collapse.timer.tick(time.delta());
+        if collapse.timer.finished() {
+            commands.entity(entity).remove::<CollapseAnimation>();
+        }
+    });
+}
+
+/**
+ * つながったブロックの塊ごとに、落とせるところまで落とす（sticky gravity）
+ * cells: 残っているブロックの座標, 戻り値: cells と同じ順番で各ブロックの新しいY座標
+ */
+pub(crate) fn sticky_collapse(cells: &[(i32, i32)]) -> Vec<i32> {
+    let index: std::collections::HashMap<(i32, i32), usize> =
+        cells.iter().enumerate().map(|(i, cell)| (*cell, i)).collect();
+
+    // 上下左右でつながっているブロックを同じ塊にまとめる
+    let mut group_of = vec![usize::MAX; cells.len()];
+    let mut groups: Vec<Vec<usize>> = vec![];
+    for start in 0..cells.len() {
+        if group_of[start] != usize::MAX {
+            continue;
+        }
+
+        let group_id = groups.len();
+        let mut members = vec![];
+        let mut stack = vec![start];
+        group_of[start] = group_id;
+        while let Some(i) = stack.pop() {
+            members.push(i);
+            let (x, y) = cells[i];
+            for (dx, dy) in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+                if let Some(&j) = index.get(&(x + dx, y + dy)) {
+                    if group_of[j] == usize::MAX {
+                        group_of[j] = group_id;
+                        stack.push(j);
+                    }
+                }
+            }
+        }
+        groups.push(members);
+    }
+
+    // 下にある塊から順に、どの塊も動かなくなるまで落とす
+    let mut ys: Vec<i32> = cells.iter().map(|(_, y)| *y).collect();
+    let mut occupied: std::collections::HashSet<(i32, i32)> = cells.iter().cloned().collect();
+    loop {
+        let mut moved = false;
+        let mut order: Vec<usize> = (0..groups.len()).collect();
+        order.sort_by_key(|g| groups[*g].iter().map(|i| ys[*i]).min().unwrap_or(0));
+
+        for g in order {
+            let members = &groups[g];
+            // 自分自身を除いた盤面で落とせる段数を調べる
+            members.iter().for_each(|i| {
+                occupied.remove(&(cells[*i].0, ys[*i]));
+            });
+            let mut down = 0;
+            while members.iter().all(|i| {
+                let y = ys[*i] - down - 1;
+                y >= 0 && !occupied.contains(&(cells[*i].0, y))
+            }) {
+                down += 1;
+            }
+            members.iter().for_each(|i| {
+                ys[*i] -= down;
+                occupied.insert((cells[*i].0, ys[*i]));
+            });
+
+            if down > 0 {
+                moved = true;
+            }
+        }
+
+        if !moved {
+            break;
+        }
+    }
+
+    ys
+}
+
+/**
+ * System: ゲームオーバー通知を受けた時の処理
+ * 盤面はそのまま残して結果画面へ移る（片付けは start_new_game で行う）
+ */
+pub(crate) fn gameover(
+    mut gameover_events: EventReader<GameOverEvent>,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    // 同じフレームに複数届いても、まとめて１回として扱う
+    if gameover_events.read().count() == 0 {
+        return;
+    }
+
+    // 結果画面へ
+    next_state.set(GameState::GameOver);
+}
+
+/**
+ * 新しいゲームを始めるのに必要なリソース
+ */
+#[derive(SystemParam)]
+pub(crate) struct NewGame<'w, 's> {
+    commands: Commands<'w, 's>,
+    block_texture: Res<'w, BlockTexture>,
+    config: Res<'w, GameConfig>,
+    initial_board: Res<'w, InitialBoard>,
+    game_board: ResMut<'w, GameBoard>,
+    run_seed: ResMut<'w, RunSeed>,
+    rng: ResMut<'w, GameRng>,
+    piece_bag: ResMut<'w, PieceBag>,
+    next_piece: ResMut<'w, NextPiece>,
+    hold: ResMut<'w, Hold>,
+    combo: ResMut<'w, Combo>,
+    score: ResMut<'w, Score>,
+    run_clock: ResMut<'w, RunClock>,
+    game_timer: ResMut<'w, GameTimer>,
+    combo_timer: ResMut<'w, ComboTimer>,
+    spawn_delay: ResMut<'w, SpawnDelay>,
+    lock_history: ResMut<'w, LockHistory>,
+    piece_counter: ResMut<'w, PieceCounter>,
+    practice_checkpoint: ResMut<'w, PracticeCheckpoint>,
+    run_assisted: ResMut<'w, RunAssisted>,
+    time_scale: Res<'w, TimeScale>,
+    mode_state: ModeState<'w>,
+    new_block_events: EventWriter<'w, NewBlockEvent>,
+    block_query: Query<'w, 's, Entity, With<Position>>,
+}
+
+/**
+ * ゲームモードごとの進行状況
+ */
+#[derive(SystemParam)]
+pub(crate) struct ModeState<'w> {
+    garbage_timer: ResMut<'w, GarbageTimer>,
+    survival_time: ResMut<'w, SurvivalTime>,
+    walls: ResMut<'w, PlayfieldWalls>,
+    shrink_timer: ResMut<'w, ShrinkTimer>,
+}
+
+/**
+ * 盤面とゲームの進行状況を初期化して、seed で新しいゲームを始める
+ * 残っているブロックはすべて取り除き、パズルの場合は初期盤面を置いてから最初のピースを要求する
+ */
+pub(crate) fn start_new_game(new_game: &mut NewGame, seed: u64) {
+    // 前のゲームのブロックと盤面を片付ける
+    new_game.block_query.iter().for_each(|entity| {
+        new_game.commands.entity(entity).despawn_recursive();
+    });
+    *new_game.game_board = GameBoard::new();
+
+    new_game.run_seed.0 = seed;
+    new_game.rng.0 = StdRng::seed_from_u64(seed);
+    new_game.piece_bag.queue.clear();
+    new_game.next_piece.0 = None;
+    *new_game.hold = Hold::default();
+    new_game.combo.0 = 0;
+    new_game.score.0 = 0;
+    new_game.run_clock.0.reset();
+    // 前のゲームの落下・コンボの途中経過や、待っていたピースの生成を持ち越さない
+    new_game.game_timer.0.reset();
+    new_game.combo_timer.0.reset();
+    new_game.spawn_delay.0 = None;
+    new_game.lock_history.0.clear();
+    // 前のゲームの練習用のチェックポイントには戻れないようにする
+    new_game.piece_counter.0 = 0;
+    new_game.practice_checkpoint.0 = None;
+
+    // せり上がりの間隔と盤面の狭まりを最初に戻す
+    let mode_state = &mut new_game.mode_state;
+    mode_state.garbage_timer.0.set_duration(std::time::Duration::from_millis(new_game.config.garbage_interval_ms));
+    mode_state.garbage_timer.0.reset();
+    mode_state.survival_time.0 = std::time::Duration::ZERO;
+    *mode_state.walls = PlayfieldWalls::default();
+    mode_state.shrink_timer.0.reset();
+
+    // スローモーションのまま始めた場合は最初から補助ありにする
+    new_game.run_assisted.0 = new_game.time_scale.0 < 1.0;
+
+    // パズルの場合は初期盤面から始める
+    if let Some(layout) = &new_game.initial_board.0 {
+        fill_initial_board(
+            &mut new_game.commands,
+            &new_game.block_texture,
+            &mut new_game.game_board,
+            layout,
+            &new_game.config,
+        );
+    }
+
+    new_game.new_block_events.send(NewBlockEvent);
+}
+
+/**
+ * System: 最初のゲームを始める
+ */
+pub(crate) fn start_first_game(mut new_game: NewGame) {
+    let seed = new_game.run_seed.0;
+    start_new_game(&mut new_game, seed);
+}
+
+/**
+ * System: 結果画面の生成
+ */
+pub(crate) fn spawn_results_text(
+    mut commands: Commands,
+    run_seed: Res<RunSeed>,
+    config: Res<GameConfig>,
+    survival_time: Res<SurvivalTime>,
+    run_assisted: Res<RunAssisted>,
+    ui_strings: Res<UiStrings>,
+) {
+    let style = |font_size: f32| TextStyle {
+        font_size,
+        color: Color::WHITE,
+        ..TextStyle::default()
+    };
+
+    let mut sections = vec![TextSection::new(format!("{}\n", ui_strings.get("game_over")), style(48.0))];
+
+    // サバイバルモードは生き残った時間が成績
+    if config.mode == GameMode::Survival {
+        let secs = survival_time.0.as_secs_f32();
+        sections.push(TextSection::new(
+            format!("{} {}:{:04.1}\n", ui_strings.get("time"), (secs / 60.0) as u32, secs % 60.0),
+            style(28.0),
+        ));
+    }
+
+    if run_assisted.0 {
+        sections.push(TextSection::new(format!("{}\n", ui_strings.get("assisted")), style(18.0)));
+    }
+
+    sections.push(TextSection::new(format!("{} {}\n\n", ui_strings.get("seed"), run_seed.0), style(18.0)));
+    sections.push(TextSection::new(
+        format!("{}\n{}", ui_strings.get("retry"), ui_strings.get("retry_same_seed")),
+        style(20.0),
+    ));
+
+    commands
+    .spawn(Text2dBundle {
+        text: Text::from_sections(sections),
+        transform: Transform::from_translation(config.board_offset().extend(10.0)),
+        ..Text2dBundle::default()
+    })
+    .insert(ResultsText);
+}
+
+/**
+ * System: 結果画面の削除
+ */
+pub(crate) fn despawn_results_text(mut commands: Commands, text_query: Query<Entity, With<ResultsText>>) {
+    text_query.iter().for_each(|entity| {
+        commands.entity(entity).despawn();
+    });
+}
+
+/**
+ * System: 結果画面から再開する
+ * Enter: 新しいシードで再開, S: 同じシードで同じピース順を再現する
+ */
+pub(crate) fn restart_from_results(
+    key_input: Res<Input<KeyCode>>,
+    mut new_game: NewGame,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    let seed = if key_input.just_pressed(KeyCode::S) {
+        new_game.run_seed.0
+    } else if key_input.just_pressed(KeyCode::Return) {
+        rand::thread_rng().gen()
+    } else {
+        return;
+    };
+
+    start_new_game(&mut new_game, seed);
+    next_state.set(GameState::Playing);
+}
+
+/**
+ * System: プレイ中に R キーで新しいシードでやり直す
+ * confirm_restart の時は RESTART_HOLD_MS 押し続けた時だけやり直し、短く押しただけでは何もしない
+ */
+pub(crate) fn restart_in_play(
+    key_input: Res<Input<KeyCode>>,
+    time: Res<Time>,
+    config: Res<GameConfig>,
+    mut restart_hold: ResMut<RestartHold>,
+    mut new_game: NewGame,
+) {
+    if !key_input.pressed(KeyCode::R) {
+        restart_hold.0.reset();
+        return;
+    }
+
+    if config.confirm_restart {
+        // 押し続けている間は一度だけやり直す（離すまで次は数えない）
+        restart_hold.0.tick(time.delta());
+        if !restart_hold.0.just_finished() {
+            return;
+        }
+    } else if !key_input.just_pressed(KeyCode::R) {
+        return;
+    }
+
+    start_new_game(&mut new_game, rand::thread_rng().gen());
+}
+
+/**
+ * System: コンボの制限時間を進める
+ */
+pub(crate) fn combo_timer(
+    time: Res<Time>,
+    config: Res<GameConfig>,
+    mut combo: ResMut<Combo>,
+    mut combo_timer: ResMut<ComboTimer>,
+) {
+    if !config.combo_timer || combo.0 == 0 {
+        return;
+    }
+
+    combo_timer.0.tick(time.delta());
+
+    // 時間切れでコンボが途切れる
+    if combo_timer.0.finished() {
+        combo.0 = 0;
+    }
+}
+
+/**
+ * System: コンボの残り時間バーの更新
+ */
+pub(crate) fn update_combo_bar(
+    config: Res<GameConfig>,
+    combo: Res<Combo>,
+    combo_timer: Res<ComboTimer>,
+    mut bar_query: Query<(&mut Sprite, &mut Transform, &mut Visibility), With<ComboBar>>,
+) {
+    // コンボの状態かタイマーが変わった時だけ更新する
+    if !config.is_changed() && !combo.is_changed() && !combo_timer.is_changed() {
+        return;
+    }
+
+    bar_query
+        .iter_mut()
+        .for_each(|(mut sprite, mut transform, mut visibility)| {
+            if !config.combo_timer || combo.0 == 0 {
+                *visibility = Visibility::Hidden;
+                return;
+            }
+
+            // 残り時間に応じて左端から縮める
+            let board_width = config.board_size().x;
+            let width = board_width * combo_timer.0.percent_left();
+            sprite.custom_size = Some(Vec2::new(width, 6.0));
+            transform.translation.x = config.board_offset().x + (width - board_width) / 2.0;
+            *visibility = Visibility::Visible;
+        });
+}
+
+/**
+ * System: ポーズの切り替え
+ */
+pub(crate) fn toggle_pause(
+    key_input: Res<Input<KeyCode>>,
+    state: Res<State<GameState>>,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    if !key_input.just_pressed(KeyCode::P) {
+        return;
+    }
+
+    match state.get() {
+        GameState::Playing => next_state.set(GameState::Paused),
+        GameState::Paused => next_state.set(GameState::Playing),
+        GameState::Options | GameState::GameOver => {}
+    }
+}
+
+/**
+ * System: 得点の表示の生成（盤面の左の余白の上端に右寄せで置く）
+ * フォントは他の文字と同じく apply_ui_font で表示する言語のものにする
+ */
+pub(crate) fn setup_score_ui(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    config: Res<GameConfig>,
+    ui_strings: Res<UiStrings>,
+) {
+    let offset = config.board_offset();
+    let board_size = config.board_size();
+    // 桁が変わっても幅が揃うように等幅のフォントを使う
+    let font = load_asset_if_exists(&asset_server, SCORE_FONT_PATH).unwrap_or_default();
+    let style = |font_size: f32| TextStyle {
+        font: font.clone(),
+        font_size,
+        color: Color::WHITE,
+    };
+
+    commands
+    .spawn(Text2dBundle {
+        text: Text::from_sections(vec![
+            TextSection::new(format!("{}\n", ui_strings.get("score")), style(18.0)),
+            TextSection::new("0", style(28.0)),
+        ])
+        .with_alignment(TextAlignment::Right),
+        text_anchor: bevy::sprite::Anchor::TopRight,
+        transform: Transform::from_xyz(
+            offset.x - board_size.x / 2.0 - BORDER_WIDTH - SIDE_PANEL_GAP,
+            offset.y + board_size.y / 2.0,
+            10.0,
+        ),
+        ..Text2dBundle::default()
+    })
+    .insert(ScoreText);
+}
+
+/**
+ * System: 次のピースとホールドの表示の見出しの生成
+ */
+pub(crate) fn setup_next_piece_ui(mut commands: Commands, config: Res<GameConfig>, ui_strings: Res<UiStrings>) {
+    for (id, center) in [("next", config.preview_center()), ("hold", config.hold_preview_center())] {
+        commands.spawn(Text2dBundle {
+            text: Text::from_section(
+                ui_strings.get(id),
+                TextStyle {
+                    font_size: 18.0,
+                    color: Color::WHITE,
+                    ..TextStyle::default()
+                },
+            ),
+            text_anchor: bevy::sprite::Anchor::BottomCenter,
+            transform: Transform::from_xyz(center.x, center.y + PREVIEW_CELL_SIZE * 2.0 + 8.0, 10.0),
+            ..Text2dBundle::default()
+        });
+    }
+}
+
+/**
+ * System: 次のピースの表示
+ * NextPiece が変わった時に作り直し、どの形でも表示の中心にピースの中心が来るように置く
+ */
+pub(crate) fn render_next_piece(
+    mut commands: Commands,
+    config: Res<GameConfig>,
+    next_piece: Res<NextPiece>,
+    preview_query: Query<Entity, With<PreviewBlock>>,
+) {
+    if !next_piece.is_changed() {
+        return;
+    }
+
+    preview_query.iter().for_each(|entity| {
+        commands.entity(entity).despawn();
+    });
+
+    if let Some((pattern, color)) = &next_piece.0 {
+        spawn_preview_blocks(&mut commands, &config, &pattern.cells, *color, config.preview_center(), PreviewBlock);
+    }
+}
+
+/**
+ * System: ホールドしているピースの表示
+ * 次のピースが出るまでホールドを使えない間は暗く表示する
+ * ホールドした時は、出てくるピースと同じように小さい状態から拡大して見せる
+ */
+pub(crate) fn render_hold_piece(
+    mut commands: Commands,
+    config: Res<GameConfig>,
+    hold: Res<Hold>,
+    preview_query: Query<Entity, With<HoldPreviewBlock>>,
+    mut was_used: Local<bool>,
+) {
+    if !hold.is_changed() {
+        return;
+    }
+
+    preview_query.iter().for_each(|entity| {
+        commands.entity(entity).despawn();
+    });
+
+    // 入れ替えたピースの生成でも変更されるので、使えなくなった時だけを数える
+    let just_held = hold.used_this_turn && !*was_used;
+    *was_used = hold.used_this_turn;
+
+    if let (Some(cells), Some(mut color)) = (&hold.pattern, hold.color) {
+        if hold.used_this_turn {
+            color.set_a(0.35);
+        }
+        let blocks = spawn_preview_blocks(&mut commands, &config, cells, color, config.hold_preview_center(), HoldPreviewBlock);
+        if just_held && !config.reduced_motion {
+            blocks.into_iter().for_each(|entity| {
+                commands
+                .entity(entity)
+                .insert(SpawnAnimation(Timer::from_seconds(SPAWN_ANIMATION_SECS, TimerMode::Once)));
+            });
+        }
+    }
+}
+
+/**
+ * 次のピース・ホールドの表示のブロックを生成して、そのエンティティを返す
+ * 形の外接する四角形の中心（半マス単位）を center に合わせるので、どの形でも中央に表示される
+ */
+pub(crate) fn spawn_preview_blocks<M: Component + Copy>(
+    commands: &mut Commands,
+    config: &GameConfig,
+    cells: &[(i32, i32)],
+    color: Color,
+    center: Vec2,
+    marker: M,
+) -> Vec<Entity> {
+    let xs = cells.iter().map(|(x, _)| *x);
+    let ys = cells.iter().map(|(_, y)| *y);
+    let mid_x2 = xs.clone().min().unwrap_or(0) + xs.max().unwrap_or(0);
+    let mid_y2 = ys.clone().min().unwrap_or(0) + ys.max().unwrap_or(0);
+
+    cells.iter().map(|(x, y)| {
+        let offset = Vec2::new(
+            (*x * 2 - mid_x2) as f32,
+            (*y * 2 - mid_y2) as f32 * config.board_up(),
+        ) * PREVIEW_CELL_SIZE / 2.0;
+        commands
+        .spawn(SpriteBundle {
+            sprite: Sprite {
+                color,
+                custom_size: Some(Vec2::splat(PREVIEW_CELL_SIZE - 2.0)),
+                ..Sprite::default()
+            },
+            transform: Transform::from_translation((center + offset).extend(BLOCK_Z)),
+            ..SpriteBundle::default()
+        })
+        .insert(marker)
+        .id()
+    })
+    .collect()
+}
+
+/**
+ * System: 得点の表示の更新（得点が変わった時だけ書き換える）
+ */
+pub(crate) fn update_score_text(score: Res<Score>, mut text_query: Query<&mut Text, With<ScoreText>>) {
+    if !score.is_changed() {
+        return;
+    }
+
+    text_query.iter_mut().for_each(|mut text| {
+        text.sections[1].value = score.0.to_string();
+    });
+}
+
+/**
+ * System: ポーズ表示の生成
+ */
+pub(crate) fn spawn_pause_text(mut commands: Commands, config: Res<GameConfig>, ui_strings: Res<UiStrings>) {
+    commands
+    .spawn(Text2dBundle {
+        text: Text::from_sections(vec![
+            TextSection::new(
+                format!("{}\n", ui_strings.get("paused")),
+                TextStyle {
+                    font_size: 48.0,
+                    color: Color::WHITE,
+                    ..TextStyle::default()
+                },
+            ),
+            TextSection::new(
+                ui_strings.get("open_options"),
+                TextStyle {
+                    font_size: 20.0,
+                    color: Color::WHITE,
+                    ..TextStyle::default()
+                },
+            ),
+        ]),
+        transform: Transform::from_translation(config.board_offset().extend(10.0)),
+        ..Text2dBundle::default()
+    })
+    .insert(PauseText);
+}
+
+/**
+ * System: ポーズ表示の削除
+ */
+pub(crate) fn despawn_pause_text(mut commands: Commands, text_query: Query<Entity, With<PauseText>>) {
+    text_query.iter().for_each(|entity| {
+        commands.entity(entity).despawn();
+    });
+}
+
+/**
+ * System: ポーズ表示の点滅
+ * ゲーム進行とは関係ないため、ポーズ中も Time に従って動かす
+ */
+pub(crate) fn blink_pause_text(time: Res<Time>, mut text_query: Query<&mut Text, With<PauseText>>) {
+    let alpha = 0.6 + 0.4 * (time.elapsed_seconds() * 3.0).sin();
+    text_query.iter_mut().for_each(|mut text| {
+        text.sections.iter_mut().for_each(|section| {
+            section.style.color.set_a(alpha);
+        });
+    });
+}
+
+/**
+ * 設定メニューの項目の表示文字列
+ */
+pub(crate) fn option_label(item: OptionItem, config: &GameConfig, ui_strings: &UiStrings) -> String {
+    let on_off = |value: bool| ui_strings.get(if value { "on" } else { "off" }).to_string();
+    let (id, value) = match item {
+        OptionItem::Gravity => ("option_gravity", format!("{} ms", config.gravity_ms)),
+        OptionItem::InputInterval => ("option_move_repeat", format!("{} ms", config.input_interval_ms)),
+        OptionItem::ComboTimer => ("option_combo_timer", on_off(config.combo_timer)),
+        OptionItem::BlockOutline => ("option_outline", on_off(config.block_outline)),
+        OptionItem::BlockOpacity => ("option_opacity", format!("{:.1}", config.block_opacity)),
+        OptionItem::Volume => ("option_volume", format!("{:.1}", config.master_volume)),
+        OptionItem::SoftDrop if !config.hard_drop => ("option_soft_drop", ui_strings.get("no_hard_drop").to_string()),
+        OptionItem::SoftDrop => ("option_soft_drop", ui_strings.get(&format!("soft_drop_{}", config.soft_drop_style)).to_string()),
+        OptionItem::VSync => ("option_vsync", on_off(config.vsync)),
+        OptionItem::PixelPerfect => ("option_crisp", on_off(config.pixel_perfect)),
+        OptionItem::MaxFps => match config.max_fps {
+            0 => ("option_max_fps", ui_strings.get("unlimited").to_string()),
+            fps => ("option_max_fps", fps.to_string()),
+        },
+        OptionItem::UiScale => ("option_text_size", format!("x{:.1}", config.ui_scale)),
+    };
+    format!("{:<width$}{}", ui_strings.get(id), value, width = OPTION_LABEL_WIDTH)
+}
+
+/**
+ * 設定メニューの項目の値を変更する（dir: +1 / -1）
+ */
+pub(crate) fn adjust_option(item: OptionItem, config: &mut GameConfig, dir: i32) {
+    match item {
+        OptionItem::Gravity => {
+            config.gravity_ms = (config.gravity_ms as i64 + 50 * dir as i64).clamp(50, 2000) as u64;
+        }
+        OptionItem::InputInterval => {
+            config.input_interval_ms = (config.input_interval_ms as i64 + 10 * dir as i64).clamp(20, 500) as u64;
+        }
+        OptionItem::ComboTimer => config.combo_timer = !config.combo_timer,
+        OptionItem::BlockOutline => config.block_outline = !config.block_outline,
+        OptionItem::BlockOpacity => {
+            config.block_opacity = (config.block_opacity + 0.1 * dir as f32).clamp(0.1, 1.0);
+        }
+        OptionItem::Volume => {
+            config.master_volume = (config.master_volume + 0.1 * dir as f32).clamp(0.0, 1.0);
+        }
+        // 一気に落とす操作を使えない時は切り替えられない
+        OptionItem::SoftDrop if !config.hard_drop => {}
+        OptionItem::SoftDrop => {
+            config.soft_drop_style = match config.soft_drop_style {
+                SoftDropStyle::Instant => SoftDropStyle::Gradual,
+                SoftDropStyle::Gradual => SoftDropStyle::Instant,
+            };
+        }
+        OptionItem::VSync => config.vsync = !config.vsync,
+        OptionItem::PixelPerfect => config.pixel_perfect = !config.pixel_perfect,
+        OptionItem::MaxFps => {
+            let current = FPS_CHOICES.iter().position(|fps| *fps == config.max_fps).unwrap_or(0) as i32;
+            let next = (current + dir).rem_euclid(FPS_CHOICES.len() as i32);
+            config.max_fps = FPS_CHOICES[next as usize];
+        }
+        OptionItem::UiScale => {
+            config.ui_scale = (config.ui_scale + 0.1 * dir as f32).clamp(MIN_UI_SCALE, MAX_UI_SCALE);
+        }
+    }
+}
+
+/**
+ * System: ポーズ中に設定メニューを開く
+ */
+pub(crate) fn open_options(
+    key_input: Res<Input<KeyCode>>,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    if key_input.just_pressed(KeyCode::O) {
+        next_state.set(GameState::Options);
+    }
+}
+
+/**
+ * System: 設定メニューの生成
+ */
+pub(crate) fn spawn_options_text(mut commands: Commands, config: Res<GameConfig>) {
+    let style = TextStyle {
+        font_size: 24.0,
+        color: Color::WHITE,
+        ..TextStyle::default()
+    };
+
+    commands
+    .spawn(Text2dBundle {
+        text: Text::from_sections(OPTION_ITEMS.iter().map(|_| TextSection::new("", style.clone())))
+            .with_alignment(TextAlignment::Left),
+        transform: Transform::from_translation(config.board_offset().extend(10.0)),
+        ..Text2dBundle::default()
+    })
+    .insert(OptionsText);
+}
+
+/**
+ * System: 設定メニューの削除
+ */
+pub(crate) fn despawn_options_text(mut commands: Commands, text_query: Query<Entity, With<OptionsText>>) {
+    text_query.iter().for_each(|entity| {
+        commands.entity(entity).despawn();
+    });
+}
+
+/**
+ * System: 設定メニューのキー操作
+ * ↑↓: 項目の選択, ←→: 値の変更, Enter: 切り替え, Esc / O: 戻る
+ */
+pub(crate) fn options_menu_input(
+    key_input: Res<Input<KeyCode>>,
+    mut menu: ResMut<OptionsMenu>,
+    mut config: ResMut<GameConfig>,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    if key_input.just_pressed(KeyCode::Escape) || key_input.just_pressed(KeyCode::O) {
+        next_state.set(GameState::Paused);
+        return;
+    }
+
+    if key_input.just_pressed(KeyCode::Up) {
+        menu.cursor = (menu.cursor + OPTION_ITEMS.len() - 1) % OPTION_ITEMS.len();
+    }
+    if key_input.just_pressed(KeyCode::Down) {
+        menu.cursor = (menu.cursor + 1) % OPTION_ITEMS.len();
+    }
+
+    let item = OPTION_ITEMS[menu.cursor];
+    if key_input.just_pressed(KeyCode::Left) {
+        adjust_option(item, &mut config, -1);
+    }
+    if key_input.just_pressed(KeyCode::Right) || key_input.just_pressed(KeyCode::Return) {
+        adjust_option(item, &mut config, 1);
+    }
+}
+
+/**
+ * System: 設定メニューの表示の更新
+ */
+pub(crate) fn update_options_text(
+    menu: Res<OptionsMenu>,
+    config: Res<GameConfig>,
+    ui_strings: Res<UiStrings>,
+    mut text_query: Query<(&mut Text, Ref<OptionsText>)>,
+) {
+    // カーソルか設定が変わった時と、メニューを開いた直後だけ書き換える
+    let refresh = menu.is_changed() || config.is_changed();
+
+    text_query.iter_mut().for_each(|(mut text, options_text)| {
+        if !refresh && !options_text.is_added() {
+            return;
+        }
+
+        text.sections
+            .iter_mut()
+            .zip(OPTION_ITEMS.iter())
+            .enumerate()
+            .for_each(|(i, (section, item))| {
+                let cursor = if i == menu.cursor { "> " } else { "  " };
+                section.value = format!("{}{}\n", cursor, option_label(*item, &config, &ui_strings));
+                section.style.color = if i == menu.cursor { Color::YELLOW } else { Color::WHITE };
+            });
+    });
+}
+
+/**
+ * System: 設定メニューを閉じる時に設定を反映して保存する
+ */
+pub(crate) fn apply_options(
+    config: Res<GameConfig>,
+    mut game_timer: ResMut<GameTimer>,
+    mut input_timer: ResMut<InputTimer>,
+    mut audio_settings: ResMut<AudioSettings>,
+    mut msaa_setting: ResMut<Msaa>,
+    mut window_query: Query<&mut Window>,
+) {
+    game_timer.0.set_duration(config.gravity_interval());
+    input_timer.0.set_duration(std::time::Duration::from_millis(config.input_interval_ms));
+    *audio_settings = AudioSettings::from_config(&config);
+    *msaa_setting = msaa(config.pixel_perfect);
+    window_query.iter_mut().for_each(|mut window| {
+        window.present_mode = present_mode(config.vsync);
+    });
+
+    if let Err(e) = config.save(CONFIG_PATH) {
+        eprintln!("config: failed to save {}: {}", CONFIG_PATH, e);
+    }
+}
+
+/**
+ * System: 音量の変更とミュート
+ * + / - : 全体の音量, M : ミュート切り替え
+ */
+pub(crate) fn audio_hotkeys(
+    key_input: Res<Input<KeyCode>>,
+    mut audio_settings: ResMut<AudioSettings>,
+    mut config: ResMut<GameConfig>,
+) {
+    let volume_up = key_input.any_just_pressed([KeyCode::Equals, KeyCode::NumpadAdd]);
+    let volume_down = key_input.any_just_pressed([KeyCode::Minus, KeyCode::NumpadSubtract]);
+    let mute = key_input.just_pressed(KeyCode::M);
+
+    if !volume_up && !volume_down && !mute {
+        return;
+    }
+
+    if volume_up {
+        audio_settings.master = (audio_settings.master + 0.1).min(1.0);
+    }
+    if volume_down {
+        audio_settings.master = (audio_settings.master - 0.1).max(0.0);
+    }
+    if mute {
+        audio_settings.muted = !audio_settings.muted;
+    }
+
+    // 設定ファイルにも保存する
+    config.master_volume = audio_settings.master;
+    config.muted = audio_settings.muted;
+    if let Err(e) = config.save(CONFIG_PATH) {
+        eprintln!("config: failed to save {}: {}", CONFIG_PATH, e);
+    }
+}
+
+/**
+ * System: 再生中の音声に音量設定を反映する
+ */
+pub(crate) fn apply_audio_volume(
+    audio_settings: Res<AudioSettings>,
+    sink_query: Query<(&AudioSink, Option<&Music>)>,
+) {
+    if !audio_settings.is_changed() {
+        return;
+    }
+
+    sink_query.iter().for_each(|(sink, music)| {
+        sink.set_volume(audio_settings.volume(music.is_some()));
+    });
+}
+
+/**
+ * System: BGM の読み込み
+ */
+pub(crate) fn setup_music(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(MusicTracks {
+        menu: load_asset_if_exists(&asset_server, "sounds/bgm_menu.ogg"),
+        playing: load_asset_if_exists(&asset_server, "sounds/bgm_playing.ogg"),
+        current: None,
+    });
+}
+
+/**
+ * System: 効果音の読み込み
+ */
+pub(crate) fn setup_sound_effects(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(SoundEffects {
+        lock: load_asset_if_exists(&asset_server, "sounds/lock.ogg"),
+        lock_danger: load_asset_if_exists(&asset_server, "sounds/lock_danger.ogg"),
+    });
+}
+
+/**
+ * System: ピースを固定した時の効果音
+ * 固定した後の積み上がりが DANGER_STACK_HEIGHT 以上なら、危険を知らせる別の音を鳴らす
+ * おじゃまブロックやパズルの初期盤面はピースではない（PieceId がない）ので鳴らさない
+ */
+pub(crate) fn play_lock_sound(
+    mut commands: Commands,
+    config: Res<GameConfig>,
+    audio_settings: Res<AudioSettings>,
+    sound_effects: Res<SoundEffects>,
+    game_board: Res<GameBoard>,
+    locked_query: Query<(), (Added<Fix>, With<PieceId>)>,
+) {
+    if locked_query.is_empty() {
+        return;
+    }
+
+    let danger = config.danger_lock_sound && game_board.stack_height() >= DANGER_STACK_HEIGHT;
+    let sound = if danger {
+        &sound_effects.lock_danger
+    } else {
+        &sound_effects.lock
+    };
+    let Some(source) = sound.clone() else {
+        return;
+    };
+
+    commands.spawn(AudioBundle {
+        source,
+        settings: audio_settings.sfx_playback(),
+    });
+}
+
+/**
+ * System: ゲームの状態に合わせて BGM を切り替える
+ * 今の BGM はフェードアウトさせ、次の BGM をフェードインさせる
+ */
+pub(crate) fn switch_music(
+    mut commands: Commands,
+    state: Res<State<GameState>>,
+    mut tracks: ResMut<MusicTracks>,
+    audio_settings: Res<AudioSettings>,
+    music_query: Query<Entity, With<Music>>,
+) {
+    let kind = match state.get() {
+        GameState::Playing => MusicKind::Playing,
+        _ => MusicKind::Menu,
+    };
+    if tracks.current == Some(kind) {
+        return;
+    }
+    tracks.current = Some(kind);
+
+    music_query.iter().for_each(|entity| {
+        commands.entity(entity).insert(MusicFade {
+            timer: Timer::new(std::time::Duration::from_millis(MUSIC_FADE_MS), TimerMode::Once),
+            fade_in: false,
+        });
+    });
+
+    // ファイルが無ければ前の BGM を止めるだけにする
+    let track = match kind {
+        MusicKind::Menu => &tracks.menu,
+        MusicKind::Playing => &tracks.playing,
+    };
+    let Some(source) = track.clone() else {
+        return;
+    };
+    commands
+    .spawn(AudioBundle {
+        source,
+        settings: audio_settings.music_playback().with_volume(Volume::new_relative(0.0)),
+    })
+    .insert(Music)
+    .insert(MusicFade {
+        timer: Timer::new(std::time::Duration::from_millis(MUSIC_FADE_MS), TimerMode::Once),
+        fade_in: true,
+    });
+}
+
+/**
+ * System: BGM のフェード
+ * 音声ファイルの読み込み前（AudioSink が無い間）も時間は進める
+ */
+pub(crate) fn fade_music(
+    mut commands: Commands,
+    time: Res<Time>,
+    audio_settings: Res<AudioSettings>,
+    mut fade_query: Query<(Entity, Option<&AudioSink>, &mut MusicFade)>,
+) {
+    fade_query.iter_mut().for_each(|(entity, sink, mut fade)| {
+        fade.timer.tick(time.delta());
+
+        let rate = if fade.fade_in {
+            fade.timer.percent()
+        } else {
+            fade.timer.percent_left()
+        };
+        if let Some(sink) = sink {
+            sink.set_volume(audio_settings.volume(true) * rate);
+        }
+
+        if !fade.timer.finished() {
+            return;
+        }
+        if fade.fade_in {
+            commands.entity(entity).remove::<MusicFade>();
+        } else {
+            commands.entity(entity).despawn();
+        }
+    });
+}
+
+/**
+ * System: invisible モードで固定されたブロックを消え始めさせる
+ * 見た目だけを変え、ゲーム盤面はそのまま
+ */
+pub(crate) fn start_invisible_fade(
+    mut commands: Commands,
+    config: Res<GameConfig>,
+    fixed_block_query: Query<Entity, Added<Fix>>,
+) {
+    if config.mode != GameMode::Invisible {
+        return;
+    }
+
+    fixed_block_query.iter().for_each(|entity| {
+        commands.entity(entity).insert(FadeOut {
+            delay: Timer::from_seconds(INVISIBLE_DELAY_SECS, TimerMode::Once),
+            fade: Timer::from_seconds(INVISIBLE_FADE_SECS, TimerMode::Once),
+        });
+    });
+}
+
+/**
+ * System: ブロックのフェードアウト
+ * V キーを押している間は一時的に全て表示する
+ */
+pub(crate) fn fade_out_blocks(
+    time: Res<Time>,
+    key_input: Res<Input<KeyCode>>,
+    config: Res<GameConfig>,
+    mut fade_query: Query<(&mut FadeOut, &mut Sprite, Option<&Children>)>,
+    mut child_query: Query<&mut Sprite, Without<FadeOut>>,
+    mut run_assisted: ResMut<RunAssisted>,
+) {
+    let reveal = key_input.pressed(KeyCode::V);
+    if reveal && !fade_query.is_empty() {
+        run_assisted.0 = true;
+    }
+
+    fade_query.iter_mut().for_each(|(mut fade, mut sprite, children)| {
+        fade.delay.tick(time.delta());
+        if fade.delay.finished() {
+            fade.fade.tick(time.delta());
+        }
+
+        let rate = if reveal { 1.0 } else { fade.fade.percent_left() };
+        let alpha = config.block_opacity * rate;
+        sprite.color.set_a(alpha);
+
+        // 縁取りも一緒に消す
+        if let Some(children) = children {
+            children.iter().for_each(|child| {
+                if let Ok(mut child_sprite) = child_query.get_mut(*child) {
+                    child_sprite.color.set_a(alpha);
+                }
+            });
+        }
+    });
+}
+
+/**
+ * System: 生成されたピースを小さい状態から元の大きさまで拡大する
+ * Transform の scale だけを変えるので、縁取り（子のスプライト）も一緒に拡大される
+ */
+pub(crate) fn animate_spawn(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut animation_query: Query<(Entity, &mut SpawnAnimation, &mut Transform)>,
+) {
+    animation_query.iter_mut().for_each(|(entity, mut animation, mut transform)| {
+        animation.0.tick(time.delta());
+
+        let rate = animation.0.percent();
+        let scale = SPAWN_ANIMATION_START_SCALE + (1.0 - SPAWN_ANIMATION_START_SCALE) * rate;
+        transform.scale = Vec3::new(scale, scale, 1.0);
+
+        if animation.0.finished() {
+            commands.entity(entity).remove::<SpawnAnimation>();
+        }
+    });
+}
+
+/**
+ * デバッグ用にマスの座標から決める色（x で色相、y で明るさを変える）
+ */
+pub(crate) fn coordinate_color(x: i32, y: i32) -> Color {
+    let hue = 360.0 * x as f32 / X_LENGTH as f32;
+    let lightness = 0.25 + 0.5 * (y as f32 / Y_LENGTH as f32).clamp(0.0, 1.0);
+    Color::hsl(hue, 0.8, lightness)
+}
+
+/**
+ * System: 操作中のピースと固定されたブロックの見た目を区別する
+ * 操作中のピースは白に寄せて明るくする（透明度は他の演出に任せてそのまま）
+ * デバッグ用の座標の色分けが有効な時は、本来の色の代わりに座標から決めた色にする
+ */
+pub(crate) fn tint_blocks(
+    config: Res<GameConfig>,
+    coordinate_colors: Res<CoordinateColors>,
+    mut block_query: Query<(&mut Sprite, &BlockColor, Option<&Free>, &Position)>,
+) {
+    block_query.iter_mut().for_each(|(mut sprite, base, free, pos)| {
+        let base_color = if coordinate_colors.0 { coordinate_color(pos.x, pos.y) } else { base.0 };
+        let [r, g, b, _] = base_color.as_rgba_f32();
+        let alpha = sprite.color.a();
+
+        sprite.color = if free.is_some() && config.highlight_active {
+            let lighten = |c: f32| c + (1.0 - c) * 0.3;
+            Color::rgba(lighten(r), lighten(g), lighten(b), alpha)
+        } else {
+            Color::rgba(r, g, b, alpha)
+        };
+    });
+}
+
+/**
+ * System: 操作中のピースが落ちる位置から床までの列に影を付ける（縦に揃える時の目安）
+ * 影はブロックより後ろに置くので、積み上がったブロックの隙間にだけ見える
+ * 補助機能なので、表示したゲームは補助ありにする
+ */
+pub(crate) fn update_column_shadow(
+    config: Res<GameConfig>,
+    game_board: Res<GameBoard>,
+    free_block_query: Query<(Entity, &mut Position, &Free)>,
+    mut shadow_query: Query<(&ColumnShadow, &mut Sprite, &mut Transform, &mut Visibility)>,
+    mut run_assisted: ResMut<RunAssisted>,
+) {
+    let cells = active_piece_cells(&free_block_query);
+    let shown = config.column_shadow && !cells.is_empty();
+    if shown {
+        run_assisted.0 = true;
+    }
+
+    // 落ちた後の各列の一番下のブロックの位置
+    let landed = offset_cells(&cells, 0, -drop_distance(&game_board, &cells));
+    let cell = config.cell_size();
+
+    shadow_query
+        .iter_mut()
+        .for_each(|(shadow, mut sprite, mut transform, mut visibility)| {
+            let bottom = landed
+                .iter()
+                .filter(|(x, _)| *x == shadow.0)
+                .map(|(_, y)| (*y).min(Y_LENGTH as i32))
+                .min();
+            let Some(bottom) = bottom.filter(|bottom| shown && *bottom > 0) else {
+                *visibility = Visibility::Hidden;
+                return;
+            };
+
+            // 床のマスから落ちた先の１つ手前のマスまで（inverted モードでも同じ計算で上下が逆になる）
+            let center = (board_to_world(shadow.0, 0, &config) + board_to_world(shadow.0, bottom - 1, &config)) / 2.0;
+            sprite.custom_size = Some(Vec2::new(cell.x, bottom as f32 * cell.y));
+            transform.translation = center.extend(SHADOW_Z);
+            *visibility = Visibility::Visible;
+        });
+}
+
+/**
+ * System: 操作中のピースが落ちる位置に半透明のピース（ゴースト）を表示する
+ * 落とす時と同じ drop_distance で位置を決め、ピースが動いた時や盤面が変わった時だけ作り直す
+ * 床に着いている時はピースと同じ位置に重なる
+ * ghost_fade_ms の時は、移動・回転のキーを押すと表示し直し、操作しない間は薄くしていく
+ */
+pub(crate) fn render_ghost(
+    mut commands: Commands,
+    (config, game_board): (Res<GameConfig>, Res<GameBoard>),
+    (key_input, time, mut ghost_fade): (Res<Input<KeyCode>>, Res<Time>, ResMut<GhostFade>),
+    free_query: Query<(&Position, &BlockColor), With<Free>>,
+    moved_query: Query<(), (With<Free>, Changed<Position>)>,
+    mut removed_free: RemovedComponents<Free>,
+    mut ghost_query: Query<(Entity, &GhostBlock, &mut Sprite)>,
+) {
+    ghost_fade.0.tick(time.delta());
+    if key_input.any_pressed([KeyCode::Left, KeyCode::Right, KeyCode::Up, KeyCode::Down]) {
+        ghost_fade.0.reset();
+    }
+    let visibility = if config.ghost_fade_ms > 0 { ghost_fade.0.percent_left() } else { 1.0 };
+
+    let locked = removed_free.read().count() > 0;
+    if moved_query.is_empty() && !locked && !game_board.is_changed() {
+        // 作り直さない時も、薄くしていく分だけ色を変える
+        ghost_query.iter_mut().for_each(|(_, ghost, mut sprite)| {
+            sprite.color.set_a(ghost.0 * visibility);
+        });
+        return;
+    }
+
+    ghost_query.iter().for_each(|(entity, _, _)| {
+        commands.entity(entity).despawn();
+    });
+
+    let Some((_, color)) = free_query.iter().next() else {
+        return;
+    };
+    if !config.ghost_piece {
+        return;
+    }
+
+    let mut ghost_color = color.0;
+    ghost_color.set_a(0.3 * visibility);
+    let cells: Vec<(i32, i32)> = free_query.iter().map(|(pos, _)| (pos.x, pos.y)).collect();
+    let landed = offset_cells(&cells, 0, -drop_distance(&game_board, &cells));
+
+    landed.iter().for_each(|(x, y)| {
+        commands
+        .spawn(SpriteBundle {
+            sprite: Sprite {
+                color: ghost_color,
+                custom_size: Some(config.cell_size()),
+                ..Sprite::default()
+            },
+            transform: Transform::from_translation(board_to_world(*x, *y, &config).extend(GHOST_Z)),
+            ..SpriteBundle::default()
+        })
+        .insert(GhostBlock(0.3));
+    });
+
+    // 固定する前に、揃って消える行を行全体に重ねて見せる
+    if !config.ghost_clear_rows {
+        return;
+    }
+    ghost_clear_rows(&game_board, &landed).iter().for_each(|y| {
+        let center = Vec2::new(config.board_offset().x, board_to_world(0, *y, &config).y);
+        let mut color = GHOST_CLEAR_COLOR;
+        color.set_a(GHOST_CLEAR_COLOR.a() * visibility);
+        commands
+        .spawn(SpriteBundle {
+            sprite: Sprite {
+                color,
+                custom_size: Some(Vec2::new(config.board_size().x, config.cell_size().y)),
+                ..Sprite::default()
+            },
+            transform: Transform::from_translation(center.extend(GHOST_Z)),
+            ..SpriteBundle::default()
+        })
+        .insert(GhostBlock(GHOST_CLEAR_COLOR.a()));
+    });
+}
+
+/**
+ * 落ちる位置 landed でピースを固定したら揃う行（盤面にピースのブロックを重ねて調べる）
+ */
+pub(crate) fn ghost_clear_rows(game_board: &GameBoard, landed: &[(i32, i32)]) -> Vec<i32> {
+    let mut rows: Vec<i32> = landed.iter().map(|(_, y)| *y).collect();
+    rows.sort();
+    rows.dedup();
+    rows.retain(|y| (0..X_LENGTH as i32).all(|x| game_board.get(x, *y) == Some(true) || landed.contains(&(x, *y))));
+    rows
+}
+
+/**
+ * 積み上がりの高さに応じた落下間隔の倍率
+ * 低く積めている時は速く（0.6倍）、高く積み上がっている時は少し遅く（1.4倍）する
+ */
+pub(crate) fn adaptive_gravity_factor(stack_height: i32) -> f32 {
+    let rate = (stack_height as f32 / Y_LENGTH as f32).clamp(0.0, 1.0);
+    0.6 + 0.8 * rate
+}
+
+/**
+ * プレイ時間の表示文字列（分:秒）
+ */
+pub(crate) fn format_run_time(elapsed: std::time::Duration) -> String {
+    let secs = elapsed.as_secs();
+    format!("{:02}:{:02}", secs / 60, secs % 60)
+}
+
+/**
+ * System: プレイ時間を進める
+ * ゲーム進行と一緒に止まるように、ポーズ中とゲームオーバー後は動かさない
+ */
+pub(crate) fn tick_run_clock(time: Res<Time>, mut run_clock: ResMut<RunClock>) {
+    run_clock.0.tick(time.delta());
+}
+
+/**
+ * System: プレイ時間の表示
+ */
+pub(crate) fn update_run_clock_text(
+    config: Res<GameConfig>,
+    run_clock: Res<RunClock>,
+    mut text_query: Query<&mut Text, With<RunClockText>>,
+) {
+    let value = if config.show_timer {
+        format_run_time(run_clock.0.elapsed())
+    } else {
+        String::new()
+    };
+
+    // 秒が変わった時だけ書き換えて文字の再配置を避ける
+    text_query.iter_mut().for_each(|mut text| {
+        if text.sections[0].value != value {
+            text.sections[0].value = value.clone();
+        }
+    });
+}
+
+/**
+ * System: 積み上がりの高さのゲージの更新
+ * 見えている盤面の高さに対する割合だけ伸ばし、低い時は緑、高くなるほど赤に近づける
+ */
+pub(crate) fn update_height_meter(
+    config: Res<GameConfig>,
+    game_board: Res<GameBoard>,
+    mut meter_query: Query<&mut Sprite, With<HeightMeter>>,
+) {
+    if !game_board.is_changed() {
+        return;
+    }
+
+    let rate = (game_board.stack_height() as f32 / Y_LENGTH as f32).clamp(0.0, 1.0);
+    meter_query.iter_mut().for_each(|mut sprite| {
+        sprite.custom_size = Some(Vec2::new(HEIGHT_METER_WIDTH, config.board_size().y * rate));
+        sprite.color = Color::rgb(0.2 + 0.7 * rate, 0.8 - 0.7 * rate, 0.2);
+    });
+}
+
+/**
+ * System: サバイバルモードで次にせり上がってくるおじゃまブロックの予告
+ * せり上がりのタイマーから残り時間を出し、近づくほど列を濃くする
+ */
+pub(crate) fn update_garbage_warning(
+    config: Res<GameConfig>,
+    garbage_timer: Res<GarbageTimer>,
+    ui_strings: Res<UiStrings>,
+    mut warning_query: Query<(&mut Sprite, &mut Visibility), With<GarbageWarning>>,
+    mut text_query: Query<&mut Text, With<GarbageWarningText>>,
+) {
+    let show = config.mode == GameMode::Survival && config.garbage_warning;
+
+    warning_query.iter_mut().for_each(|(mut sprite, mut visibility)| {
+        *visibility = if show { Visibility::Visible } else { Visibility::Hidden };
+        let mut color = DANGER_COLOR;
+        color.set_a(0.3 + 0.6 * garbage_timer.0.percent());
+        sprite.color = color;
+    });
+
+    let value = if show {
+        format!(
+            "{} +{} {:.1}s",
+            ui_strings.get("garbage"),
+            GARBAGE_LINES_PER_RISE,
+            garbage_timer.0.remaining_secs(),
+        )
+    } else {
+        String::new()
+    };
+
+    // 表示が変わる時だけ書き換えて文字の再配置を避ける
+    text_query.iter_mut().for_each(|mut text| {
+        if text.sections[0].value != value {
+            text.sections[0].value = value.clone();
+        }
+    });
+}
+
+/**
+ * System: 積み上がり具合に応じて落下速度を調整する
+ */
+pub(crate) fn adaptive_gravity(
+    config: Res<GameConfig>,
+    game_board: Res<GameBoard>,
+    mut game_timer: ResMut<GameTimer>,
+) {
+    if !config.adaptive_gravity {
+        return;
+    }
+
+    let factor = adaptive_gravity_factor(game_board.stack_height());
+    let gravity_ms = (config.gravity_ms as f32 * config.gravity_multiplier * factor) as u64;
+    let duration = std::time::Duration::from_millis(gravity_ms.clamp(MIN_GRAVITY_MS, MAX_GRAVITY_MS));
+
+    if game_timer.0.duration() != duration {
+        game_timer.0.set_duration(duration);
+    }
+}
+
+/**
+ * System: 落下間隔の倍率の変更
+ * [ : 10% 遅くする, ] : 10% 速くする（設定ファイルにも保存する）
+ */
+pub(crate) fn gravity_hotkeys(
+    key_input: Res<Input<KeyCode>>,
+    mut config: ResMut<GameConfig>,
+    mut game_timer: ResMut<GameTimer>,
+) {
+    let step = if key_input.just_pressed(KeyCode::BracketLeft) {
+        GRAVITY_MULTIPLIER_STEP
+    } else if key_input.just_pressed(KeyCode::BracketRight) {
+        1.0 / GRAVITY_MULTIPLIER_STEP
+    } else {
+        return;
+    };
+
+    config.gravity_multiplier = (config.gravity_multiplier * step).clamp(MIN_GRAVITY_MULTIPLIER, MAX_GRAVITY_MULTIPLIER);
+    // adaptive_gravity の時はこの後に adaptive_gravity が倍率を反映する
+    let interval = config.gravity_interval();
+    game_timer.0.set_duration(interval);
+
+    if let Err(e) = config.save(CONFIG_PATH) {
+        eprintln!("config: failed to save {}: {}", CONFIG_PATH, e);
+    }
+}
+
+/**
+ * System: 現在の落下速度の表示
+ * 設定した落下間隔に対する速さの倍率を表示する（積み上がりや [ / ] キーで変えている時だけ）
+ */
+pub(crate) fn update_gravity_text(
+    config: Res<GameConfig>,
+    game_timer: Res<GameTimer>,
+    ui_strings: Res<UiStrings>,
+    mut text_query: Query<&mut Text, With<GravityText>>,
+) {
+    let value = if config.adaptive_gravity || config.gravity_multiplier != 1.0 {
+        let duration = game_timer.0.duration().as_millis();
+        let speed = config.gravity_ms as f32 / duration as f32;
+        format!("{} x{:.2} ({} ms)", ui_strings.get("speed"), speed, duration)
+    } else {
+        String::new()
+    };
+
+    // GameTimer は毎フレーム進むので、表示が変わる時だけ書き換えて文字の再配置を避ける
+    text_query.iter_mut().for_each(|mut text| {
+        if text.sections[0].value != value {
+            text.sections[0].value = value.clone();
+        }
+    });
+}
+
+/**
+ * 次のピースがどの形でも生成できるか（どれか１つでも生成位置が塞がっていれば false）
+ */
+pub(crate) fn can_spawn_any(game_board: &GameBoard, block_patterns: &[BlockPattern], walls: &PlayfieldWalls) -> bool {
+    block_patterns.iter().all(|pattern| {
+        let positions: Vec<(i32, i32)> = spawn_cells(&pattern.cells, walls).iter().map(|(pos, _)| *pos).collect();
+        can_occupy(game_board, &positions)
+    })
+}
+
+/**
+ * System: 次のピースでゲームオーバーになりそうな時に盤面の枠を赤く点滅させる
+ * reduced_motion の時は点滅させずに赤くするだけにする
+ */
+pub(crate) fn warn_topout(
+    time: Res<Time>,
+    config: Res<GameConfig>,
+    game_board: Res<GameBoard>,
+    block_patterns: Res<BlockPatterns>,
+    walls: Res<PlayfieldWalls>,
+    mut border_query: Query<&mut Sprite, With<BoardBorder>>,
+) {
+    let color = if can_spawn_any(&game_board, &block_patterns.0, &walls) {
+        BORDER_COLOR
+    } else if config.reduced_motion {
+        DANGER_COLOR
+    } else {
+        let rate = 0.5 + 0.5 * (time.elapsed_seconds() * 6.0).sin();
+        let [r1, g1, b1, _] = BORDER_COLOR.as_rgba_f32();
+        let [r2, g2, b2, _] = DANGER_COLOR.as_rgba_f32();
+        Color::rgb(r1 + (r2 - r1) * rate, g1 + (g2 - g1) * rate, b1 + (b2 - b1) * rate)
+    };
+
+    border_query.iter_mut().for_each(|mut sprite| {
+        sprite.color = color;
+    });
+}
+
+/**
+ * 固定したピースを元に戻すのに必要なリソース
+ */
+#[derive(SystemParam)]
+pub(crate) struct Undo<'w> {
+    lock_history: ResMut<'w, LockHistory>,
+    game_board: ResMut<'w, GameBoard>,
+    run_assisted: ResMut<'w, RunAssisted>,
+    block_patterns: Res<'w, BlockPatterns>,
+    piece_bag: ResMut<'w, PieceBag>,
+    next_piece: ResMut<'w, NextPiece>,
+}
+
+/**
+ * System: 練習モードで最後に固定したピースを元に戻す（Ctrl + Z）
+ * 固定したピースを盤面から取り除き、生成位置に操作中のピースとして戻す
+ * 操作中だったピースは次のピースに戻し、ピースの順番は変えない
+ * ライン消去でブロックが消えたり動いたりした後は戻せない
+ */
+pub(crate) fn undo_lock(
+    mut commands: Commands,
+    (key_input, config, walls): (Res<Input<KeyCode>>, Res<GameConfig>, Res<PlayfieldWalls>),
+    mut undo: Undo,
+    free_block_query: Query<(Entity, &RelativePosition, &RotationCenter, &BlockColor), With<Free>>,
+    mut fixed_block_query: Query<(Entity, &mut Position, &RelativePosition, &PieceId), With<Fix>>,
+) {
+    if config.mode != GameMode::Practice {
+        return;
+    }
+    let ctrl = key_input.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]);
+    if !ctrl || !key_input.just_pressed(KeyCode::Z) {
+        return;
+    }
+
+    let Some(locked) = undo.lock_history.0.pop() else {
+        return;
+    };
+
+    // 固定した時のまま残っているか確認する
+    let mut blocks: Vec<(i32, i32)> = fixed_block_query
+        .iter()
+        .filter(|(_, _, _, piece_id)| **piece_id == locked.piece_id)
+        .map(|(_, pos, _, _)| (pos.x, pos.y))
+        .collect();
+    let mut cells = locked.cells.clone();
+    blocks.sort();
+    cells.sort();
+    if blocks != cells {
+        println!("Cannot undo after a line clear");
+        undo.lock_history.0.clear();
+        return;
+    }
+
+    // 盤面から取り除いてから生成位置に置けるか確認する
+    cells.iter().for_each(|(x, y)| {
+        undo.game_board.set(*x, *y, false);
+    });
+    let spawn_blocked = fixed_block_query
+        .iter()
+        .filter(|(_, _, _, piece_id)| **piece_id == locked.piece_id)
+        .any(|(_, _, r_pos, _)| {
+            let ((x, y), _) = spawn_cells(&[(r_pos.rot_x, r_pos.rot_y)], &walls)[0];
+            !undo.game_board.is_vacant(x, y)
+        });
+    if spawn_blocked {
+        cells.iter().for_each(|(x, y)| {
+            undo.game_board.set(*x, *y, true);
+        });
+        undo.lock_history.0.push(locked);
+        return;
+    }
+
+    // 今操作中のピースは取り除いて次のピースに戻し、次のピースだったものは袋の先頭に戻す
+    let relative: Vec<(i32, i32)> = free_block_query.iter().map(|(_, r_pos, _, _)| (r_pos.rot_x, r_pos.rot_y)).collect();
+    let displaced = free_block_query.iter().next().and_then(|(_, _, center, color)| {
+        spawn_orientation(&undo.block_patterns.0, &relative, center)
+            .and_then(|cells| find_pattern(&undo.block_patterns.0, &cells))
+            .map(|pattern| (pattern, color.0))
+    });
+    if let Some(displaced) = displaced {
+        let Undo { block_patterns, piece_bag, next_piece, .. } = &mut undo;
+        if let Some((pattern, _)) = next_piece.0.replace(displaced) {
+            if let Some(index) = block_patterns.0.iter().position(|p| p.cells == pattern.cells) {
+                piece_bag.queue.push_front(index);
+            }
+        }
+    }
+    free_block_query.iter().for_each(|(entity, _, _, _)| {
+        commands.entity(entity).despawn_recursive();
+    });
+
+    undo.run_assisted.0 = true;
+
+    // 戻したピースを操作中にする
+    fixed_block_query
+        .iter_mut()
+        .filter(|(_, _, _, piece_id)| **piece_id == locked.piece_id)
+        .for_each(|(entity, mut pos, r_pos, _)| {
+            let ((x, y), _) = spawn_cells(&[(r_pos.rot_x, r_pos.rot_y)], &walls)[0];
+            pos.x = x;
+            pos.y = y;
+            commands.entity(entity).remove::<Fix>();
+            commands.entity(entity).insert(Free);
+        });
+}
+
+/**
+ * チェックポイントに保存して戻す、ブロック以外のゲームの状態
+ */
+#[derive(SystemParam)]
+pub(crate) struct PracticeState<'w> {
+    game_board: ResMut<'w, GameBoard>,
+    rng: ResMut<'w, GameRng>,
+    piece_counter: ResMut<'w, PieceCounter>,
+    combo: ResMut<'w, Combo>,
+    score: ResMut<'w, Score>,
+    piece_bag: ResMut<'w, PieceBag>,
+    next_piece: ResMut<'w, NextPiece>,
+    hold: ResMut<'w, Hold>,
+}
+
+// チェックポイントに保存するブロック（操作中のピースのブロックだけが PieceId などを持つ）
+type SavedBlockQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        &'static Position,
+        &'static BlockColor,
+        Option<&'static PieceId>,
+        Option<&'static RelativePosition>,
+        Option<&'static RotationCenter>,
+        Option<&'static Free>,
+    ),
+>;
+
+/**
+ * System: 練習モードで今の盤面と操作中のピースをチェックポイントとして保存する（F5）
+ */
+pub(crate) fn save_checkpoint(
+    key_input: Res<Input<KeyCode>>,
+    config: Res<GameConfig>,
+    state: PracticeState,
+    mut checkpoint: ResMut<PracticeCheckpoint>,
+    block_query: SavedBlockQuery,
+) {
+    if config.mode != GameMode::Practice || !key_input.just_pressed(KeyCode::F5) {
+        return;
+    }
+
+    let blocks = block_query
+        .iter()
+        .map(|(pos, color, piece_id, r_pos, center, free)| SavedBlock {
+            x: pos.x,
+            y: pos.y,
+            color: color.0,
+            piece_id: piece_id.copied(),
+            relative: r_pos.map(|r_pos| (r_pos.rot_x, r_pos.rot_y)),
+            center: center.copied(),
+            free: free.is_some(),
+        })
+        .collect();
+
+    checkpoint.0 = Some(Checkpoint {
+        board: state.game_board.0.clone(),
+        blocks,
+        rng: state.rng.0.clone(),
+        piece_counter: state.piece_counter.0,
+        combo: state.combo.0,
+        score: state.score.0,
+        piece_bag: state.piece_bag.clone(),
+        next_piece: state.next_piece.clone(),
+        hold: state.hold.clone(),
+    });
+    println!("Checkpoint saved");
+}
+
+/**
+ * System: 練習モードで保存したチェックポイントに戻す（F9）
+ * ブロックをすべて作り直し、乱数も戻すので、この後に出てくるピースも保存した時と同じになる
+ * 戻した後は元に戻す（Ctrl + Z）の履歴は使えない
+ */
+pub(crate) fn restore_checkpoint(
+    mut commands: Commands,
+    // システムの引数の数を抑えるために、いくつかまとめている
+    (key_input, config, block_texture): (Res<Input<KeyCode>>, Res<GameConfig>, Res<BlockTexture>),
+    checkpoint: Res<PracticeCheckpoint>,
+    mut state: PracticeState,
+    (mut lock_history, mut game_timer, mut run_assisted): (ResMut<LockHistory>, ResMut<GameTimer>, ResMut<RunAssisted>),
+    mut new_block_events: EventWriter<NewBlockEvent>,
+    block_query: Query<Entity, With<Position>>,
+) {
+    if config.mode != GameMode::Practice || !key_input.just_pressed(KeyCode::F9) {
+        return;
+    }
+    let Some(saved) = &checkpoint.0 else {
+        return;
+    };
+
+    block_query.iter().for_each(|entity| {
+        commands.entity(entity).despawn_recursive();
+    });
+
+    state.game_board.0 = saved.board.clone();
+    state.rng.0 = saved.rng.clone();
+    state.piece_counter.0 = saved.piece_counter;
+    state.combo.0 = saved.combo;
+    state.score.0 = saved.score;
+    *state.piece_bag = saved.piece_bag.clone();
+    *state.next_piece = saved.next_piece.clone();
+    *state.hold = saved.hold.clone();
+    lock_history.0.clear();
+    run_assisted.0 = true;
+
+    saved.blocks.iter().for_each(|block| {
+        let mut entity = spawn_block_sprite(&mut commands, &block_texture, block.color, &config);
+        entity.insert(Position { x: block.x, y: block.y });
+        if let Some(piece_id) = block.piece_id {
+            entity.insert(piece_id);
+        }
+        if let Some((rot_x, rot_y)) = block.relative {
+            entity.insert(RelativePosition { rot_x, rot_y });
+        }
+        if let Some(center) = block.center {
+            entity.insert(center);
+        }
+        if block.free {
+            entity.insert(Free);
+        } else {
+            entity.insert(Fix);
+        }
+    });
+
+    // ピースを固定した直後に保存していた場合は次のピースを出す
+    if !saved.blocks.iter().any(|block| block.free) {
+        new_block_events.send(NewBlockEvent);
+    }
+    game_timer.0.reset();
+}
+
+/**
+ * System: ウィンドウを閉じて終了する時に設定を保存する
+ * AppExit は PostUpdate で送られるので、同じフレームの Last で受け取れる
+ */
+pub(crate) fn save_on_exit(mut exit_events: EventReader<bevy::app::AppExit>, config: Res<GameConfig>) {
+    if exit_events.read().next().is_none() {
+        return;
+    }
+
+    if let Err(e) = config.save(CONFIG_PATH) {
+        eprintln!("config: failed to save {}: {}", CONFIG_PATH, e);
+    }
+}
+
+/**
+ * ピースを置いた後の盤面の評価（高いほど良い）
+ * 消えるライン数を加点し、積み上がりの高さの合計・穴・列ごとの高さの凸凹を減点する
+ */
+pub(crate) fn evaluate_placement(game_board: &GameBoard, cells: &[(i32, i32)]) -> f32 {
+    let height = game_board.height();
+    let filled = |x: i32, y: i32| game_board.get(x, y) == Some(true) || cells.contains(&(x, y));
+
+    let lines = (0..height).filter(|y| (0..X_LENGTH as i32).all(|x| filled(x, *y))).count() as i32;
+
+    // 消えるラインは除いて数える
+    let mut column_heights = vec![];
+    let mut holes = 0;
+    for x in 0..X_LENGTH as i32 {
+        let column: Vec<bool> = (0..height)
+            .filter(|y| !(0..X_LENGTH as i32).all(|x| filled(x, *y)))
+            .map(|y| filled(x, y))
+            .collect();
+        let column_height = column.iter().rposition(|cell| *cell).map_or(0, |y| y + 1);
+        holes += column[..column_height].iter().filter(|cell| !**cell).count() as i32;
+        column_heights.push(column_height as i32);
+    }
+    let aggregate_height: i32 = column_heights.iter().sum();
+    let bumpiness: i32 = column_heights.windows(2).map(|pair| (pair[0] - pair[1]).abs()).sum();
+
+    0.76 * lines as f32 - 0.51 * aggregate_height as f32 - 0.36 * holes as f32 - 0.18 * bumpiness as f32
+}
+
+// ピースの１ブロック分の（盤面の座標，ピースの原点からの相対座標）
+type PieceCell = ((i32, i32), (i32, i32));
+
+/**
+ * 操作中のピースのおすすめの置き場所（置いた時のブロックの座標、置ける場所がなければ None）
+ * ４つの向きと盤面の幅の分の横位置だけを試すので、計算量は一定に収まる
+ * 今の高さで置ける位置から真下に落とした場所だけを考え、そこまで動かせるかは調べない
+ */
+pub(crate) fn best_placement(game_board: &GameBoard, piece: &[PieceCell], center: &RotationCenter) -> Option<Vec<(i32, i32)>> {
+    let ((pos_x, pos_y), (r_x, r_y)) = *piece.first()?;
+    let (origin_x, origin_y) = (pos_x - r_x, pos_y - r_y);
+
+    let mut relative: Vec<(i32, i32)> = piece.iter().map(|(_, r_pos)| *r_pos).collect();
+    let mut best = None;
+    for _ in 0..4 {
+        for x in -(X_LENGTH as i32)..=X_LENGTH as i32 {
+            let cells: Vec<(i32, i32)> = relative.iter().map(|(r_x, r_y)| (x + r_x, origin_y + r_y)).collect();
+            if !can_occupy(game_board, &cells) {
+                continue;
+            }
+
+            let landed = offset_cells(&cells, 0, -drop_distance(game_board, &cells));
+            let score = evaluate_placement(game_board, &landed);
+            // 同じ評価なら今の位置に近い方を選ぶ
+            let better = match &best {
+                None => true,
+                Some((best_score, best_x, _)) => {
+                    score > *best_score || (score == *best_score && (x - origin_x).abs() < (best_x - origin_x).abs())
+                }
+            };
+            if better {
+                best = Some((score, x, landed));
+            }
+        }
+        relative = relative.iter().map(|r_pos| rotate_relative(*r_pos, center)).collect();
+    }
+
+    best.map(|(_, _, cells)| cells)
+}
+
+/**
+ * System: H キーを押している間、操作中のピースのおすすめの置き場所を表示する（自動では動かさない）
+ * 補助機能なので、使ったゲームは補助ありにする
+ */
+pub(crate) fn show_move_hint(
+    key_input: Res<Input<KeyCode>>,
+    config: Res<GameConfig>,
+    game_board: Res<GameBoard>,
+    free_block_query: Query<(&Position, &RelativePosition, &RotationCenter), With<Free>>,
+    mut hint_query: Query<(&mut Transform, &mut Visibility), With<HintCell>>,
+    mut run_assisted: ResMut<RunAssisted>,
+) {
+    let placement = if key_input.pressed(KeyCode::H) {
+        let piece: Vec<PieceCell> = free_block_query
+            .iter()
+            .map(|(pos, r_pos, _)| ((pos.x, pos.y), (r_pos.rot_x, r_pos.rot_y)))
+            .collect();
+        free_block_query
+            .iter()
+            .next()
+            .and_then(|(_, _, center)| best_placement(&game_board, &piece, center))
+    } else {
+        None
+    };
+    if placement.is_some() {
+        run_assisted.0 = true;
+    }
+
+    let cells = placement.unwrap_or_default();
+    hint_query
+        .iter_mut()
+        .enumerate()
+        .for_each(|(i, (mut transform, mut visibility))| {
+            let Some((x, y)) = cells.get(i) else {
+                *visibility = Visibility::Hidden;
+                return;
+            };
+            transform.translation = board_to_world(*x, *y, &config).extend(BLOCK_Z + 0.5);
+            *visibility = Visibility::Visible;
+        });
+}
+
+/**
+ * 垂直同期の設定に対応する表示モード
+ */
+pub(crate) fn present_mode(vsync: bool) -> PresentMode {
+    if vsync {
+        PresentMode::AutoVsync
+    } else {
+        PresentMode::AutoNoVsync
+    }
+}
+
+/**
+ * ピクセルをそのまま表示する設定に対応するアンチエイリアスの設定
+ */
+pub(crate) fn msaa(pixel_perfect: bool) -> Msaa {
+    if pixel_perfect {
+        Msaa::Off
+    } else {
+        Msaa::Sample4
+    }
+}
+
+/**
+ * System: フレームレートの上限を守るようにフレームの終わりで待つ
+ * 落下や入力のタイマーは経過時間で進むため、上限を変えてもゲームの速さは変わらない
+ * （１フレームが落下間隔より長くなるほど下げた場合は、１フレームに１マスしか落ちない）
+ */
+pub(crate) fn limit_frame_rate(config: Res<GameConfig>, mut limiter: ResMut<FrameLimiter>) {
+    if config.max_fps > 0 {
+        let frame_time = std::time::Duration::from_secs_f64(1.0 / config.max_fps as f64);
+        let elapsed = limiter.0.elapsed();
+        if elapsed < frame_time {
+            std::thread::sleep(frame_time - elapsed);
+        }
+    }
+
+    limiter.0 = std::time::Instant::now();
+}
+
+/**
+ * System: 新しく生成された文字の元の文字サイズを記録する
+ */
+pub(crate) fn record_base_font_size(mut commands: Commands, text_query: Query<(Entity, &Text), Added<Text>>) {
+    text_query.iter().for_each(|(entity, text)| {
+        let sizes = text.sections.iter().map(|section| section.style.font_size).collect();
+        commands.entity(entity).insert(BaseFontSize(sizes));
+    });
+}
+
+/**
+ * System: 新しく生成された文字に表示する言語のフォントを使う（指定が無ければ標準のフォントのまま）
+ */
+pub(crate) fn apply_ui_font(
+    asset_server: Res<AssetServer>,
+    ui_strings: Res<UiStrings>,
+    mut text_query: Query<&mut Text, Added<Text>>,
+) {
+    let Some(path) = &ui_strings.font else {
+        return;
+    };
+    let font: Handle<Font> = asset_server.load(path.clone());
+
+    text_query.iter_mut().for_each(|mut text| {
+        text.sections.iter_mut().for_each(|section| {
+            section.style.font = font.clone();
+        });
+    });
+}
+
+/**
+ * System: 全ての文字に拡大率を反映する
+ * 文字は盤面と同じワールド座標に置いているので、ウィンドウの大きさが変わっても盤面との位置関係は保たれる
+ */
+pub(crate) fn apply_ui_scale(config: Res<GameConfig>, mut text_query: Query<(&mut Text, &BaseFontSize)>) {
+    let scale = config.ui_scale;
+
+    text_query.iter_mut().for_each(|(mut text, base)| {
+        let needs_update = text
+            .sections
+            .iter()
+            .zip(base.0.iter())
+            .any(|(section, size)| section.style.font_size != size * scale);
+        if !needs_update {
+            return;
+        }
+
+        text.sections
+            .iter_mut()
+            .zip(base.0.iter())
+            .for_each(|(section, size)| {
+                section.style.font_size = size * scale;
+            });
+    });
+}
+
+/**
+ * サバイバルモードでおじゃまブロックをせり上げるのに使うリソース
+ */
+#[derive(SystemParam)]
+pub(crate) struct Survival<'w> {
+    garbage_timer: ResMut<'w, GarbageTimer>,
+    survival_time: ResMut<'w, SurvivalTime>,
+    rng: ResMut<'w, GameRng>,
+    block_texture: Res<'w, BlockTexture>,
+}
+
+/**
+ * System: サバイバルモードでおじゃまブロックをせり上げる
+ * 一定間隔で盤面の下に１行追加し、せり上がるたびに間隔を短くする
+ * 押し上げられて生成位置が塞がれば、通常どおりブロック生成時にゲームオーバーになる
+ */
+pub(crate) fn rise_garbage(
+    mut commands: Commands,
+    time: Res<Time>,
+    config: Res<GameConfig>,
+    mut survival: Survival,
+    mut game_board: ResMut<GameBoard>,
+    mut fix_query: Query<&mut Position, (With<Fix>, Without<Free>)>,
+    mut free_query: Query<&mut Position, (With<Free>, Without<Fix>)>,
+) {
+    if config.mode != GameMode::Survival {
+        return;
+    }
+
+    survival.survival_time.0 += time.delta();
+    survival.garbage_timer.0.tick(time.delta());
+    if !survival.garbage_timer.0.just_finished() {
+        return;
+    }
+
+    // 固定されたブロックを１段押し上げて、一番下におじゃまブロックの行を追加
+    let hole = survival.rng.0.gen_range(0..X_LENGTH as i32);
+    game_board.insert_garbage_line(hole);
+    fix_query.iter_mut().for_each(|mut pos| {
+        pos.y += 1;
+    });
+    for x in (0..X_LENGTH as i32).filter(|x| *x != hole) {
+        spawn_block_sprite(&mut commands, &survival.block_texture, Color::GRAY, &config)
+        .insert(Position { x, y: 0 })
+        .insert(Fix);
+    }
+
+    // 操作中のピースに重なったら重ならなくなるまで押し上げる
+    while free_query.iter().any(|pos| !game_board.is_vacant(pos.x, pos.y)) {
+        free_query.iter_mut().for_each(|mut pos| {
+            pos.y += 1;
+        });
+    }
+
+    // 次のせり上がりまでの間隔を縮める
+    let interval = survival.garbage_timer.0.duration().mul_f32(GARBAGE_SPEEDUP);
+    survival.garbage_timer.0.set_duration(interval.max(std::time::Duration::from_millis(MIN_GARBAGE_INTERVAL_MS)));
+}
+
+/**
+ * デバッグ用エディタでクリックした位置のマスを探して、ブロックを置く・消すのに使うもの
+ */
+#[derive(SystemParam)]
+pub(crate) struct BoardEditor<'w, 's> {
+    window_query: Query<'w, 's, &'static Window, With<PrimaryWindow>>,
+    camera_query: Query<'w, 's, (&'static Camera, &'static GlobalTransform)>,
+    game_board: ResMut<'w, GameBoard>,
+    block_texture: Res<'w, BlockTexture>,
+}
+
+/**
+ * System: デバッグ用エディタでクリックしたマスのブロックを置く・消す
+ */
+pub(crate) fn edit_board_on_click(
+    mut commands: Commands,
+    mouse_input: Res<Input<MouseButton>>,
+    config: Res<GameConfig>,
+    mut editor: BoardEditor,
+    fix_query: Query<(Entity, &Position), With<Fix>>,
+    free_query: Query<&Position, With<Free>>,
+    mut run_assisted: ResMut<RunAssisted>,
+) {
+    if !config.debug || !mouse_input.just_pressed(MouseButton::Left) {
+        return;
+    }
+
+    // クリックした位置をワールド座標に変換
+    let (Ok(window), Ok((camera, camera_transform))) = (editor.window_query.get_single(), editor.camera_query.get_single()) else {
+        return;
+    };
+    let Some(world) = window
+        .cursor_position()
+        .and_then(|cursor| camera.viewport_to_world_2d(camera_transform, cursor))
+    else {
+        return;
+    };
+
+    let (x, y) = world_to_board(world.x, world.y, &config);
+    if x < 0 || x >= X_LENGTH as i32 || y < 0 || y >= Y_LENGTH as i32 {
+        return;
+    }
+
+    // 操作中のピースがあるマスは編集しない
+    if free_query.iter().any(|pos| pos.x == x && pos.y == y) {
+        return;
+    }
+
+    run_assisted.0 = true;
+
+    if editor.game_board.get(x, y) == Some(true) {
+        editor.game_board.set(x, y, false);
+        fix_query
+            .iter()
+            .filter(|(_, pos)| pos.x == x && pos.y == y)
+            .for_each(|(entity, _)| {
+                commands.entity(entity).despawn_recursive();
+            });
+    } else {
+        editor.game_board.set(x, y, true);
+        spawn_block_sprite(&mut commands, &editor.block_texture, Color::GRAY, &config)
+        .insert(Position { x, y })
+        .insert(Fix);
+    }
+}
+
+/**
+ * System: デバッグ用エディタで現在の盤面をパズルファイルに書き出す（F2）
+ */
+pub(crate) fn export_board(
+    key_input: Res<Input<KeyCode>>,
+    config: Res<GameConfig>,
+    game_board: Res<GameBoard>,
+) {
+    if !config.debug || !key_input.just_pressed(KeyCode::F2) {
+        return;
+    }
+
+    match std::fs::write(PUZZLE_EXPORT_PATH, format_initial_board(&game_board)) {
+        Ok(()) => println!("Board exported to {}", PUZZLE_EXPORT_PATH),
+        Err(e) => eprintln!("puzzle: {}: {}", PUZZLE_EXPORT_PATH, e),
+    }
+}
+
+/**
+ * System: デバッグ用のスローモーションを切り替える（F3）
+ * 落下と入力のタイマーだけを遅くする
+ */
+pub(crate) fn toggle_slow_motion(
+    key_input: Res<Input<KeyCode>>,
+    config: Res<GameConfig>,
+    mut time_scale: ResMut<TimeScale>,
+    mut text_query: Query<&mut Text, With<SlowMotionText>>,
+    mut run_assisted: ResMut<RunAssisted>,
+    ui_strings: Res<UiStrings>,
+) {
+    if !config.debug || !key_input.just_pressed(KeyCode::F3) {
+        return;
+    }
+
+    time_scale.0 = if time_scale.0 < 1.0 { 1.0 } else { SLOW_MOTION_SCALE };
+    if time_scale.0 < 1.0 {
+        run_assisted.0 = true;
+    }
+
+    text_query.iter_mut().for_each(|mut text| {
+        text.sections[0].value = if time_scale.0 < 1.0 {
+            format!("{} x{}", ui_strings.get("slow"), time_scale.0)
+        } else {
+            String::new()
+        };
+    });
+}
+
+/**
+ * System: デバッグ用にブロックを盤面の座標で色分けする表示を切り替える（F4）
+ */
+pub(crate) fn toggle_coordinate_colors(
+    key_input: Res<Input<KeyCode>>,
+    config: Res<GameConfig>,
+    mut coordinate_colors: ResMut<CoordinateColors>,
+) {
+    if !config.debug || !key_input.just_pressed(KeyCode::F4) {
+        return;
+    }
+
+    coordinate_colors.0 = !coordinate_colors.0;
+}
+
+/**
+ * 固定されたブロックの位置と盤面が一致しているか調べ、食い違いを文字列で返す
+ * 壁で埋めた列は盤面の高さ全体が埋まっているはず（壁のブロックは見えている範囲にしか置かない）
+ */
+pub(crate) fn board_sync_errors(game_board: &GameBoard, fixed: &Vec<(i32, i32)>, walls: &PlayfieldWalls) -> Vec<String> {
+    let mut errors = vec![];
+    let mut expected = std::collections::HashSet::new();
+    for (x, y) in fixed {
+        if !expected.insert((*x, *y)) {
+            errors.push(format!("two fixed blocks at ({}, {})", x, y));
+        }
+    }
+    for x in (0..walls.left).chain(X_LENGTH as i32 - walls.right..X_LENGTH as i32) {
+        for y in 0..game_board.height() {
+            expected.insert((x, y));
+        }
+    }
+
+    for y in 0..game_board.height() {
+        for x in 0..X_LENGTH as i32 {
+            let filled = game_board.get(x, y) == Some(true);
+            if filled != expected.contains(&(x, y)) {
+                let problem = if filled { "filled without a fixed block" } else { "empty under a fixed block" };
+                errors.push(format!("board cell ({}, {}) is {}", x, y, problem));
+            }
+        }
+    }
+    // 盤面の範囲外にある固定されたブロック
+    for (x, y) in fixed {
+        if game_board.get(*x, *y).is_none() {
+            errors.push(format!("fixed block at ({}, {}) is outside the board", x, y));
+        }
+    }
+
+    errors
+}
+
+/**
+ * System: デバッグ用に固定されたブロックと盤面が食い違っていないか調べる（F6）
+ * 食い違いはすべてログに出し、デバッグビルドでは止める
+ */
+pub(crate) fn check_board_sync(
+    key_input: Res<Input<KeyCode>>,
+    config: Res<GameConfig>,
+    game_board: Res<GameBoard>,
+    walls: Res<PlayfieldWalls>,
+    fix_query: Query<&Position, With<Fix>>,
+) {
+    if !config.debug || !key_input.just_pressed(KeyCode::F6) {
+        return;
+    }
+
+    let fixed: Vec<(i32, i32)> = fix_query.iter().map(|pos| (pos.x, pos.y)).collect();
+    let errors = board_sync_errors(&game_board, &fixed, &walls);
+    if errors.is_empty() {
+        println!("Board sync OK ({} fixed blocks)", fixed.len());
+        return;
+    }
+
+    errors.iter().for_each(|error| {
+        eprintln!("board sync: {}", error);
+    });
+    debug_assert!(errors.is_empty(), "board sync: {} mismatches", errors.len());
+}
+
+/**
+ * System: キー入力をフレーム番号付きで記録する（押した・離したの両方）
+ * 常に動かしておき、記録が input_log_size を超えたら古いものから捨てる
+ */
+pub(crate) fn record_input(key_input: Res<Input<KeyCode>>, mut input_log: ResMut<InputLog>) {
+    input_log.frame += 1;
+    let frame = input_log.frame;
+
+    let pressed = key_input.get_just_pressed().map(|key| (frame, *key, true));
+    let released = key_input.get_just_released().map(|key| (frame, *key, false));
+    let new_entries: Vec<_> = pressed.chain(released).collect();
+
+    for entry in new_entries {
+        if input_log.entries.len() >= input_log.capacity {
+            input_log.entries.pop_front();
+        }
+        if input_log.capacity > 0 {
+            input_log.entries.push_back(entry);
+        }
+    }
+}
+
+/**
+ * 外部のツール向けにゲームの状態を JSON にする
+ * board は load_initial_board と同じく上の行から並べ、cells は盤面の座標、shape はピースの原点からの相対座標
+ */
+pub(crate) fn format_state_json(
+    game_board: &GameBoard,
+    piece: &[PieceCell],
+    config: &GameConfig,
+    seed: u64,
+    combo: u32,
+) -> String {
+    let rows: Vec<String> = format_initial_board(game_board)
+        .lines()
+        .map(|row| format!("\"{}\"", row))
+        .collect();
+    let points = |points: Vec<(i32, i32)>| -> String {
+        let points: Vec<String> = points.iter().map(|(x, y)| format!("[{}, {}]", x, y)).collect();
+        format!("[{}]", points.join(", "))
+    };
+    let active_piece = if piece.is_empty() {
+        "null".to_string()
+    } else {
+        format!(
+            "{{\"cells\": {}, \"shape\": {}}}",
+            points(piece.iter().map(|(pos, _)| *pos).collect()),
+            points(piece.iter().map(|(_, r_pos)| *r_pos).collect()),
+        )
+    };
+
+    format!(
+        "{{\n  \"version\": {},\n  \"seed\": {},\n  \"mode\": \"{}\",\n  \"combo\": {},\n  \"width\": {},\n  \"height\": {},\n  \"board\": [\n    {}\n  ],\n  \"active_piece\": {}\n}}\n",
+        STATE_EXPORT_VERSION,
+        seed,
+        config.mode,
+        combo,
+        X_LENGTH,
+        Y_LENGTH,
+        rows.join(",\n    "),
+        active_piece,
+    )
+}
+
+/**
+ * System: ゲームの状態を JSON で書き出す（F7）
+ */
+pub(crate) fn export_state(
+    key_input: Res<Input<KeyCode>>,
+    config: Res<GameConfig>,
+    game_board: Res<GameBoard>,
+    run_seed: Res<RunSeed>,
+    combo: Res<Combo>,
+    free_block_query: Query<(&Position, &RelativePosition), With<Free>>,
+) {
+    if !key_input.just_pressed(KeyCode::F7) {
+        return;
+    }
+
+    let mut piece: Vec<PieceCell> = free_block_query
+        .iter()
+        .map(|(pos, r_pos)| ((pos.x, pos.y), (r_pos.rot_x, r_pos.rot_y)))
+        .collect();
+    piece.sort();
+
+    let json = format_state_json(&game_board, &piece, &config, run_seed.0, combo.0);
+    match std::fs::write(STATE_EXPORT_PATH, json) {
+        Ok(()) => println!("State exported to {}", STATE_EXPORT_PATH),
+        Err(e) => eprintln!("state export: {}: {}", STATE_EXPORT_PATH, e),
+    }
+}
+
+/**
+ * System: 記録したキー入力をファイルに書き出す（F12）
+ */
+pub(crate) fn dump_input_log(key_input: Res<Input<KeyCode>>, input_log: Res<InputLog>) {
+    if !key_input.just_pressed(KeyCode::F12) {
+        return;
+    }
+
+    let text: String = input_log
+        .entries
+        .iter()
+        .map(|(frame, key, pressed)| {
+            format!("{} {:?} {}\n", frame, key, if *pressed { "press" } else { "release" })
+        })
+        .collect();
+
+    match std::fs::write(INPUT_LOG_PATH, text) {
+        Ok(()) => println!("Input log written to {}", INPUT_LOG_PATH),
+        Err(e) => eprintln!("input log: {}: {}", INPUT_LOG_PATH, e),
+    }
+}
+
+/**
+ * shrink モードで盤面を狭めるのに使うリソース
+ */
+#[derive(SystemParam)]
+pub(crate) struct Shrink<'w> {
+    shrink_timer: ResMut<'w, ShrinkTimer>,
+    walls: ResMut<'w, PlayfieldWalls>,
+    game_board: ResMut<'w, GameBoard>,
+    block_texture: Res<'w, BlockTexture>,
+}
+
+/**
+ * System: shrink モードで一定間隔ごとに盤面の端の列を壁で埋める（右と左を交互に）
+ * 壁の列にあったブロックは壁に置き換え、操作中のピースが重なったら内側に押し出す
+ * 置ける範囲が MIN_PLAYFIELD_WIDTH より狭くなる時はゲームオーバー
+ */
+pub(crate) fn shrink_playfield(
+    mut commands: Commands,
+    time: Res<Time>,
+    config: Res<GameConfig>,
+    mut shrink: Shrink,
+    mut gameover_events: EventWriter<GameOverEvent>,
+    fix_query: Query<(Entity, &Position), With<Fix>>,
+    mut free_query: Query<&mut Position, (With<Free>, Without<Fix>)>,
+) {
+    if config.mode != GameMode::Shrink {
+        return;
+    }
+
+    shrink.shrink_timer.0.tick(time.delta());
+    if !shrink.shrink_timer.0.just_finished() {
+        return;
+    }
+
+    if shrink.walls.width() - 1 < MIN_PLAYFIELD_WIDTH {
+        gameover_events.send(GameOverEvent);
+        println!("Game Over");
+        return;
+    }
+
+    // 右から埋め始めて、左右交互に埋める
+    let (x, inward) = if shrink.walls.right <= shrink.walls.left {
+        shrink.walls.right += 1;
+        (X_LENGTH as i32 - shrink.walls.right, -1)
+    } else {
+        shrink.walls.left += 1;
+        (shrink.walls.left - 1, 1)
+    };
+
+    // 埋める列にあったブロックは壁になる
+    fix_query
+        .iter()
+        .filter(|(_, pos)| pos.x == x)
+        .for_each(|(entity, _)| {
+            commands.entity(entity).despawn_recursive();
+        });
+    for y in 0..shrink.game_board.height() {
+        shrink.game_board.set(x, y, true);
+    }
+    for y in 0..Y_LENGTH as i32 {
+        spawn_block_sprite(&mut commands, &shrink.block_texture, WALL_COLOR, &config)
+        .insert(Position { x, y })
+        .insert(Wall);
+    }
+
+    // 操作中のピースが壁に重なったら内側にずらし、それでも重なるなら上に押し出す
+    if free_query.iter().any(|pos| !shrink.game_board.is_vacant(pos.x, pos.y)) {
+        free_query.iter_mut().for_each(|mut pos| {
+            pos.x += inward;
+        });
+    }
+    while free_query.iter().any(|pos| !shrink.game_board.is_vacant(pos.x, pos.y)) {
+        free_query.iter_mut().for_each(|mut pos| {
+            pos.y += 1;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /**
+     * ウィンドウ・描画・音を使わずに、ゲームのリソースとイベントだけを用意した App
+     * キー入力は Input<KeyCode> を直接押し、時間は step で進める
+     */
+    fn test_app(config: GameConfig) -> App {
+        let gravity_time = config.gravity_interval();
+        let input_time = Duration::from_millis(config.input_interval_ms);
+        let rotate_time = Duration::from_millis(config.rotate_interval_ms);
+        let mut rotate_cooldown = Timer::new(rotate_time, TimerMode::Once);
+        rotate_cooldown.tick(rotate_time);
+        let combo_time = Duration::from_millis(config.combo_time_ms);
+        let garbage_time = Duration::from_millis(config.garbage_interval_ms);
+        let shrink_time = Duration::from_millis(config.shrink_interval_ms);
+
+        let mut app = App::new();
+        app.init_resource::<Time>()
+            .init_resource::<Input<KeyCode>>()
+            .insert_resource(BlockPatterns(standard_block_patterns()))
+            .insert_resource(Materials { colors: vec![Color::RED] })
+            .insert_resource(BlockTexture(Handle::default()))
+            .insert_resource(GameTimer(Timer::new(gravity_time, TimerMode::Repeating)))
+            .insert_resource(InputTimer(Timer::new(input_time, TimerMode::Repeating)))
+            .insert_resource(RotateCooldown(rotate_cooldown))
+            .insert_resource(RestartHold(Timer::new(Duration::from_millis(RESTART_HOLD_MS), TimerMode::Once)))
+            .insert_resource(GhostFade(Timer::new(Duration::from_millis(config.ghost_fade_ms), TimerMode::Once)))
+            .insert_resource(PieceCounter::default())
+            .insert_resource(LockHistory::default())
+            .insert_resource(PracticeCheckpoint::default())
+            .insert_resource(GameRng(StdRng::seed_from_u64(0)))
+            .insert_resource(PieceBag::default())
+            .insert_resource(NextPiece::default())
+            .insert_resource(Hold::default())
+            .insert_resource(RunSeed(0))
+            .insert_resource(RunAssisted::default())
+            .insert_resource(ComboTimer(Timer::new(combo_time, TimerMode::Once)))
+            .insert_resource(Combo::default())
+            .insert_resource(Score::default())
+            .insert_resource(RunClock::default())
+            .insert_resource(TimeScale(1.0))
+            .insert_resource(SpawnDelay::default())
+            .insert_resource(GarbageTimer(Timer::new(garbage_time, TimerMode::Repeating)))
+            .insert_resource(SurvivalTime::default())
+            .insert_resource(PlayfieldWalls::default())
+            .insert_resource(ShrinkTimer(Timer::new(shrink_time, TimerMode::Repeating)))
+            .insert_resource(GameBoard::new())
+            .insert_resource(InitialBoard(None))
+            .insert_resource(config)
+            .add_event::<NewBlockEvent>()
+            .add_event::<GameOverEvent>()
+            .add_event::<GravityTickEvent>()
+            .add_state::<GameState>();
+        app
+    }
+
+    /**
+     * 時間を duration だけ進めて１フレーム動かす（実際に掛かった時間に関係なく、毎回同じ結果になる）
+     */
+    fn step(app: &mut App, duration: Duration) {
+        app.world.resource_mut::<Time>().advance_by(duration);
+        app.update();
+        // 押した・離した瞬間の判定は１フレームだけ（InputPlugin の代わり）
+        app.world.resource_mut::<Input<KeyCode>>().clear();
+    }
+
+    /**
+     * 操作中のピースを origin を原点にして直接置く
+     */
+    fn spawn_piece(app: &mut App, pattern: &BlockPattern, (origin_x, origin_y): (i32, i32)) {
+        pattern.cells.iter().for_each(|(r_x, r_y)| {
+            app.world.spawn((
+                Position { x: origin_x + r_x, y: origin_y + r_y },
+                RelativePosition { rot_x: *r_x, rot_y: *r_y },
+                pattern.center,
+                BlockColor(Color::RED),
+                PieceId(0),
+                Free,
+            ));
+        });
+    }
+
+    /**
+     * 操作中のピースのブロックの位置（並べ替え済み）
+     */
+    fn free_cells(app: &mut App) -> Vec<(i32, i32)> {
+        let mut cells: Vec<(i32, i32)> = app
+            .world
+            .query_filtered::<&Position, With<Free>>()
+            .iter(&app.world)
+            .map(|pos| (pos.x, pos.y))
+            .collect();
+        cells.sort();
+        cells
+    }
+
+    /**
+     * 固定したブロックを直接置く（盤面にも反映する）
+     */
+    fn spawn_fixed(app: &mut App, cells: &[(i32, i32)]) {
+        cells.iter().for_each(|(x, y)| {
+            app.world.spawn((Position { x: *x, y: *y }, BlockColor(Color::GRAY), Fix));
+            app.world.resource_mut::<GameBoard>().set(*x, *y, true);
+        });
+    }
+
+    /**
+     * 固定したブロックの位置（並べ替え済み）
+     */
+    fn fixed_cells(app: &mut App) -> Vec<(i32, i32)> {
+        let mut cells: Vec<(i32, i32)> = app
+            .world
+            .query_filtered::<&Position, With<Fix>>()
+            .iter(&app.world)
+            .map(|pos| (pos.x, pos.y))
+            .collect();
+        cells.sort();
+        cells
+    }
+
+    /**
+     * 相対座標の形を origin を原点にして置いた時の位置（並べ替え済み）
+     */
+    fn placed(cells: &[(i32, i32)], (origin_x, origin_y): (i32, i32)) -> Vec<(i32, i32)> {
+        let mut cells: Vec<(i32, i32)> = cells.iter().map(|(x, y)| (origin_x + x, origin_y + y)).collect();
+        cells.sort();
+        cells
+    }
+
+    /**
+     * 左下が (0, 0) になるようにずらした形（並べ替え済み）
+     */
+    fn normalized(cells: &[(i32, i32)]) -> Vec<(i32, i32)> {
+        let min_x = cells.iter().map(|(x, _)| *x).min().unwrap_or(0);
+        let min_y = cells.iter().map(|(_, y)| *y).min().unwrap_or(0);
+        let mut cells: Vec<(i32, i32)> = cells.iter().map(|(x, y)| (x - min_x, y - min_y)).collect();
+        cells.sort();
+        cells
+    }
+
+    /**
+     * 次のピース・ホールドの表示のブロックの形（表示の中心からのずれをマス単位に直したもの）
+     */
+    fn preview_shape<M: Component>(app: &mut App, center: Vec2) -> Vec<(i32, i32)> {
+        // 表示のずれは半マス単位なので、２倍して整数にしてから戻す
+        let half_cells: Vec<(i32, i32)> = app
+            .world
+            .query_filtered::<&Transform, With<M>>()
+            .iter(&app.world)
+            .map(|transform| {
+                let offset = (transform.translation.truncate() - center) * 2.0 / PREVIEW_CELL_SIZE;
+                (offset.x.round() as i32, offset.y.round() as i32)
+            })
+            .collect();
+        normalized(&half_cells).iter().map(|(x, y)| (x / 2, y / 2)).collect()
+    }
+
+    /**
+     * 操作中のピースの数
+     */
+    fn free_piece_count(app: &mut App) -> usize {
+        let piece_ids: std::collections::HashSet<PieceId> =
+            app.world.query_filtered::<&PieceId, With<Free>>().iter(&app.world).copied().collect();
+        piece_ids.len()
+    }
+
+    /**
+     * 次に生成するピースの形
+     */
+    fn next_piece_cells(app: &App) -> Vec<(i32, i32)> {
+        app.world.resource::<NextPiece>().0.as_ref().map(|(pattern, _)| pattern.cells.clone()).unwrap_or_default()
+    }
+
+    fn press_shift(app: &mut App) {
+        let mut key_input = app.world.resource_mut::<Input<KeyCode>>();
+        key_input.release(KeyCode::ShiftLeft);
+        key_input.press(KeyCode::ShiftLeft);
+    }
+
+    fn square() -> BlockPattern {
+        standard_block_patterns()[5].clone()
+    }
+
+    /**
+     * 次に生成するピースを決めておく
+     */
+    fn set_next_piece(app: &mut App, pattern: &BlockPattern) {
+        app.world.resource_mut::<NextPiece>().0 = Some((pattern.clone(), Color::RED));
+    }
+
+    /**
+     * このフレームに送られたイベントの数
+     */
+    fn sent_events<E: Event>(app: &App) -> usize {
+        app.world.resource::<Events<E>>().iter_current_update_events().count()
+    }
+
+    #[test]
+    fn hold_on_the_lock_frame_does_not_lock_the_held_piece() {
+        let mut app = test_app(GameConfig::default());
+        app.add_systems(Update, (hold_piece, apply_deferred, block_vertical_move, apply_deferred, block_fall).chain());
+        // 床に着いているピースを、固定するフレームにホールドする
+        spawn_piece(&mut app, &square(), (4, 0));
+        app.world.send_event(GravityTickEvent);
+        app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::ShiftLeft);
+        step(&mut app, Duration::from_millis(16));
+
+        assert!(free_cells(&mut app).is_empty());
+        let fixed = app.world.query_filtered::<(), With<Fix>>().iter(&app.world).count();
+        assert_eq!(fixed, 0);
+        let game_board = app.world.resource::<GameBoard>();
+        assert!((0..X_LENGTH as i32).all(|x| game_board.is_vacant(x, 0)));
+        assert_eq!(sent_events::<NewBlockEvent>(&app), 1);
+        assert_eq!(app.world.resource::<Hold>().pattern, Some(square().cells));
+    }
+
+    #[test]
+    fn gravity_follows_the_stepped_clock() {
+        let mut app = test_app(GameConfig::default());
+        app.add_systems(Update, (game_timer, block_fall).chain());
+        spawn_piece(&mut app, &square(), (4, 10));
+
+        // 落下間隔（400 ミリ秒）に届かない間は落ちない
+        step(&mut app, Duration::from_millis(300));
+        assert_eq!(free_cells(&mut app), vec![(4, 10), (4, 11), (5, 10), (5, 11)]);
+
+        // 間隔を過ぎたら１マス
+        step(&mut app, Duration::from_millis(150));
+        assert_eq!(free_cells(&mut app), vec![(4, 9), (4, 10), (5, 9), (5, 10)]);
+
+        // 長いフレームでは過ぎた回数だけ落ちる
+        step(&mut app, Duration::from_millis(1200));
+        assert_eq!(free_cells(&mut app), vec![(4, 6), (4, 7), (5, 6), (5, 7)]);
+    }
+
+    #[test]
+    fn soft_drop_lands_on_the_floor() {
+        let mut app = test_app(GameConfig::default());
+        app.add_systems(Update, (spawn_block, game_timer, block_vertical_move).chain());
+        set_next_piece(&mut app, &square());
+        app.world.send_event(NewBlockEvent);
+        step(&mut app, Duration::from_millis(16));
+        assert_eq!(free_cells(&mut app), vec![(5, 18), (5, 19), (6, 18), (6, 19)]);
+
+        // 押し続けている間、左右移動と同じ間隔で１マスずつ床まで落ちる
+        app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::Down);
+        for _ in 0..Y_LENGTH + 5 {
+            step(&mut app, Duration::from_millis(100));
+        }
+        assert_eq!(free_cells(&mut app), vec![(5, 0), (5, 1), (6, 0), (6, 1)]);
+    }
+
+    #[test]
+    fn game_board_ignores_cells_outside_the_board() {
+        let mut game_board = GameBoard::new();
+        let width = X_LENGTH as i32;
+        let height = game_board.height();
+        let outside = [(-1, 0), (width, 0), (0, -1), (0, height), (-1, -1), (width, height)];
+
+        for (x, y) in outside {
+            game_board.set(x, y, true);
+            assert_eq!(game_board.get(x, y), None, "({}, {})", x, y);
+        }
+
+        // 範囲外に置こうとしても、端のマスには何も置かれない
+        for (x, y) in [(0, 0), (width - 1, 0), (0, height - 1), (width - 1, height - 1)] {
+            assert_eq!(game_board.get(x, y), Some(false), "({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn can_occupy_checks_the_floor_walls_and_blocks() {
+        let mut game_board = GameBoard::new();
+        let on_floor = vec![(4, 0), (5, 0), (4, 1), (5, 1)];
+
+        // 床に着いていれば下には動けない
+        assert!(can_occupy(&game_board, &on_floor));
+        assert!(!can_occupy(&game_board, &offset_cells(&on_floor, 0, -1)));
+
+        // ブロックの上に乗っていれば下には動けない
+        let on_block = offset_cells(&on_floor, 0, 3);
+        game_board.set(5, 2, true);
+        assert!(can_occupy(&game_board, &on_block));
+        assert!(!can_occupy(&game_board, &offset_cells(&on_block, 0, -1)));
+
+        // 下が空いていれば落ちられる
+        let in_the_air = offset_cells(&on_floor, 2, 5);
+        assert!(can_occupy(&game_board, &offset_cells(&in_the_air, 0, -1)));
+
+        // 端の列には置けて、その外には置けない
+        let right_edge = X_LENGTH as i32 - 1;
+        assert!(can_occupy(&game_board, &[(0, 10), (right_edge, 10)]));
+        assert!(!can_occupy(&game_board, &[(-1, 10)]));
+        assert!(!can_occupy(&game_board, &[(right_edge + 1, 10)]));
+    }
+
+    #[test]
+    fn delete_line_clears_rows_in_the_buffer_zone() {
+        let mut app = test_app(GameConfig::default());
+        app.add_systems(Update, delete_line);
+        // 見えている範囲より上で揃った行と、その上に乗っているブロック
+        let buffer_row = Y_LENGTH as i32 + 1;
+        let row: Vec<(i32, i32)> = (0..X_LENGTH as i32).map(|x| (x, buffer_row)).collect();
+        spawn_fixed(&mut app, &row);
+        spawn_fixed(&mut app, &[(3, buffer_row + 1)]);
+
+        app.world.send_event(GravityTickEvent);
+        step(&mut app, Duration::from_millis(16));
+
+        assert_eq!(fixed_cells(&mut app), vec![(3, buffer_row)]);
+        let game_board = app.world.resource::<GameBoard>();
+        assert!((0..X_LENGTH as i32).all(|x| game_board.get(x, buffer_row) == Some(x == 3)));
+        assert_eq!(game_board.get(3, buffer_row + 1), Some(false));
+    }
+
+    #[test]
+    fn sticky_gravity_drops_a_connected_group_as_a_unit() {
+        let mut app = test_app(GameConfig { line_clear_gravity: LineClearGravity::Sticky, ..GameConfig::default() });
+        app.add_systems(Update, delete_line);
+        let row: Vec<(i32, i32)> = (0..X_LENGTH as i32).map(|x| (x, 0)).collect();
+        spawn_fixed(&mut app, &row);
+        // 右端が下の空いたマスにはみ出している塊
+        spawn_fixed(&mut app, &[(1, 3), (2, 3), (2, 4), (3, 4)]);
+
+        app.world.send_event(GravityTickEvent);
+        step(&mut app, Duration::from_millis(16));
+
+        // 消した行数より多く、床まで形を保ったまま落ちる（はみ出した部分だけが先に落ちない）
+        let landed = vec![(1, 0), (2, 0), (2, 1), (3, 1)];
+        assert_eq!(fixed_cells(&mut app), landed);
+        let game_board = app.world.resource::<GameBoard>();
+        assert!(landed.iter().all(|(x, y)| game_board.get(*x, *y) == Some(true)));
+        assert_eq!(game_board.get(3, 0), Some(false));
+    }
+
+    #[test]
+    fn sticky_gravity_clears_a_chained_row_on_the_next_tick() {
+        let mut app = test_app(GameConfig { line_clear_gravity: LineClearGravity::Sticky, ..GameConfig::default() });
+        app.add_systems(Update, delete_line);
+        // y = 0 は右端だけ空いていて、y = 1 は揃っている
+        let right_edge = X_LENGTH as i32 - 1;
+        let bottom: Vec<(i32, i32)> = (0..right_edge).map(|x| (x, 0)).collect();
+        let full: Vec<(i32, i32)> = (0..X_LENGTH as i32).map(|x| (x, 1)).collect();
+        spawn_fixed(&mut app, &bottom);
+        spawn_fixed(&mut app, &full);
+        spawn_fixed(&mut app, &[(0, 2), (right_edge, 2)]);
+
+        app.world.send_event(GravityTickEvent);
+        step(&mut app, Duration::from_millis(16));
+
+        // 右端のブロックが空いたマスまで落ちて y = 0 が揃う
+        let mut expected = bottom.clone();
+        expected.extend([(0, 1), (right_edge, 0)]);
+        expected.sort();
+        assert_eq!(fixed_cells(&mut app), expected);
+        assert_eq!(app.world.resource::<Combo>().0, 1);
+
+        // 揃った行は次の落下タイミングで消える
+        app.world.send_event(GravityTickEvent);
+        step(&mut app, Duration::from_millis(16));
+
+        assert_eq!(fixed_cells(&mut app), vec![(0, 0)]);
+        assert_eq!(app.world.resource::<Combo>().0, 2);
+        assert_eq!(app.world.resource::<Score>().0, line_clear_score(1) * 2);
+    }
+
+    #[test]
+    fn one_gravity_tick_clears_lines_and_drops_the_piece_once() {
+        let mut app = test_app(GameConfig::default());
+        app.add_systems(First, delete_line);
+        app.add_systems(Update, block_fall);
+        let row: Vec<(i32, i32)> = (0..X_LENGTH as i32).map(|x| (x, 0)).collect();
+        spawn_fixed(&mut app, &row);
+        spawn_piece(&mut app, &square(), (4, 10));
+
+        // 同じ落下タイミングを First のライン消去と Update の落下がそれぞれ１回ずつ受け取る
+        app.world.send_event(GravityTickEvent);
+        step(&mut app, Duration::from_millis(16));
+
+        assert!(fixed_cells(&mut app).is_empty());
+        assert_eq!(app.world.resource::<Combo>().0, 1);
+        assert_eq!(app.world.resource::<Score>().0, line_clear_score(1));
+        assert_eq!(free_cells(&mut app), placed(&square().cells, (4, 9)));
+
+        // 次のフレームに同じ落下タイミングを読み直さない
+        step(&mut app, Duration::from_millis(16));
+        assert_eq!(app.world.resource::<Combo>().0, 1);
+        assert_eq!(free_cells(&mut app), placed(&square().cells, (4, 9)));
+    }
+
+    #[test]
+    fn many_gravity_ticks_in_one_frame_stop_on_an_overhang() {
+        let mut app = test_app(GameConfig::default());
+        app.add_systems(Update, block_fall);
+        // y = 5 の棚（左端だけ空いている）の下は床まで空いている
+        let shelf: Vec<(i32, i32)> = (1..X_LENGTH as i32).map(|x| (x, 5)).collect();
+        spawn_fixed(&mut app, &shelf);
+        spawn_piece(&mut app, &square(), (4, 15));
+
+        // 棚を通り抜けて床まで届くだけの回数を１フレームにまとめて送る
+        for _ in 0..15 {
+            app.world.send_event(GravityTickEvent);
+        }
+        step(&mut app, Duration::from_millis(16));
+
+        assert!(free_cells(&mut app).is_empty());
+        let landed = [(4, 6), (4, 7), (5, 6), (5, 7)];
+        let fixed = fixed_cells(&mut app);
+        assert!(landed.iter().all(|cell| fixed.contains(cell)), "{:?}", fixed);
+        assert_eq!(fixed.len(), shelf.len() + landed.len());
+    }
+
+    #[test]
+    fn left_and_right_together_cancel_out() {
+        // 壁際でも片方だけ動いたりしない
+        for origin in [(4, 10), (0, 10), (X_LENGTH as i32 - 2, 10)] {
+            let mut app = test_app(GameConfig::default());
+            app.add_systems(Update, (game_timer, block_horizontal_move).chain());
+            spawn_piece(&mut app, &square(), origin);
+            let before = free_cells(&mut app);
+
+            let mut key_input = app.world.resource_mut::<Input<KeyCode>>();
+            key_input.press(KeyCode::Left);
+            key_input.press(KeyCode::Right);
+            for _ in 0..3 {
+                step(&mut app, Duration::from_millis(100));
+            }
+            assert_eq!(free_cells(&mut app), before);
+
+            // 片方を離せば、もう片方の向きに動く
+            app.world.resource_mut::<Input<KeyCode>>().release(KeyCode::Left);
+            step(&mut app, Duration::from_millis(100));
+            if origin.0 + 2 < X_LENGTH as i32 {
+                assert_eq!(free_cells(&mut app), offset_cells(&before, 1, 0));
+            }
+        }
+    }
+
+    #[test]
+    fn next_piece_preview_matches_the_spawned_piece() {
+        for pattern in standard_block_patterns() {
+            let mut app = test_app(GameConfig::default());
+            app.add_systems(Update, (render_next_piece, spawn_block));
+            set_next_piece(&mut app, &pattern);
+            step(&mut app, Duration::from_millis(16));
+            let center = app.world.resource::<GameConfig>().preview_center();
+            let preview = preview_shape::<PreviewBlock>(&mut app, center);
+
+            app.world.send_event(NewBlockEvent);
+            step(&mut app, Duration::from_millis(16));
+
+            assert_eq!(preview, normalized(&free_cells(&mut app)), "{:?}", pattern.cells);
+        }
+    }
+
+    #[test]
+    fn blocked_spawn_is_nudged_up() {
+        let mut app = test_app(GameConfig::default());
+        app.add_systems(Update, spawn_block);
+        // 生成位置の下の段だけ塞がっている
+        let spawn_x = PlayfieldWalls::default().spawn_x();
+        let spawn_y = Y_LENGTH as i32;
+        spawn_fixed(&mut app, &[(spawn_x, spawn_y)]);
+        let cells = spawn_cells(&square().cells, &PlayfieldWalls::default());
+        assert_eq!(spawn_nudge(app.world.resource::<GameBoard>(), &cells), Some(1));
+
+        set_next_piece(&mut app, &square());
+        app.world.send_event(NewBlockEvent);
+        step(&mut app, Duration::from_millis(16));
+
+        let mut expected: Vec<(i32, i32)> = cells.iter().map(|((x, y), _)| (*x, *y + 1)).collect();
+        expected.sort();
+        assert_eq!(free_cells(&mut app), expected);
+        assert_eq!(sent_events::<GameOverEvent>(&app), 0);
+    }
+
+    #[test]
+    fn spawn_blocked_beyond_the_nudge_limit_is_game_over() {
+        let mut app = test_app(GameConfig::default());
+        app.add_systems(Update, spawn_block);
+        // ずらせる範囲まで生成する列がすべて塞がっている
+        let spawn_x = PlayfieldWalls::default().spawn_x();
+        let column: Vec<(i32, i32)> = (0..=Y_LENGTH as i32 + SPAWN_NUDGE_LIMIT).map(|y| (spawn_x, y)).collect();
+        spawn_fixed(&mut app, &column);
+
+        set_next_piece(&mut app, &square());
+        app.world.send_event(NewBlockEvent);
+        step(&mut app, Duration::from_millis(16));
 
-    // イベントの送信
-    new_block_events.send(NewBlockEvent);
-}
+        assert!(free_cells(&mut app).is_empty());
+        assert_eq!(sent_events::<GameOverEvent>(&app), 1);
+    }
 
-/**
- * System: 次のブロックの決定
- */
-pub(crate) fn next_block(block_patterns: &Vec<Vec<(i32, i32)>>) -> Vec<(i32, i32)> {
-    let mut rng = rand::thread_rng();
-    let mut pattern_index: usize = rng.gen();
-    pattern_index %= block_patterns.len();
+    #[test]
+    fn pending_spawn_waits_while_paused() {
+        let mut app = test_app(GameConfig { debug: true, spawn_jitter_ms: 100, ..GameConfig::default() });
+        app.add_systems(Update, spawn_block);
+        app.world.resource_mut::<NextState<GameState>>().set(GameState::Paused);
+        step(&mut app, Duration::from_millis(16));
 
-    block_patterns[pattern_index].clone()
-}
+        // ポーズ中に要求されても、待ち時間を過ぎても生成しない
+        set_next_piece(&mut app, &square());
+        app.world.send_event(NewBlockEvent);
+        for _ in 0..10 {
+            step(&mut app, Duration::from_millis(50));
+        }
+        assert!(free_cells(&mut app).is_empty());
 
-/**
- * System: ブロックの色の決定
- */
-pub(crate) fn next_color(colors: &Vec<Color>) -> Color {
-    let mut rng = rand::thread_rng();
-    let mut color_index: usize = rng.gen();
-    color_index %= colors.len();
+        // 再開したら待ち時間を数え始め、要求は取りこぼさない
+        app.world.resource_mut::<NextState<GameState>>().set(GameState::Playing);
+        for _ in 0..3 {
+            step(&mut app, Duration::from_millis(50));
+        }
+        assert_eq!(free_cells(&mut app).len(), 4);
+    }
 
-    colors[color_index]
-}
+    #[test]
+    #[should_panic(expected = "new piece overlaps an occupied cell")]
+    fn spawning_onto_a_block_missing_from_the_board_is_caught() {
+        let mut app = test_app(GameConfig::default());
+        app.add_systems(Update, spawn_block);
+        // 盤面には反映されていないブロックが生成位置に残っている
+        let spawn_x = PlayfieldWalls::default().spawn_x();
+        app.world.spawn((Position { x: spawn_x, y: Y_LENGTH as i32 }, BlockColor(Color::GRAY), Fix));
 
-/**
- * System: ブロックの生成
- */
-pub(crate) fn spawn_block(
-    mut commands: Commands,
-    materials: Res<Materials>,
-    block_patterns: Res<BlockPatterns>,
-    mut new_block_event_reader: EventReader<NewBlockEvent>,
-    game_board: ResMut<GameBoard>,
-    mut gameover_events: ResMut<Events<GameOverEvent>>,
-) {
-    if new_block_event_reader
-        .read()
-        .next()
-        .is_none()
-    {
-        return;
+        set_next_piece(&mut app, &square());
+        app.world.send_event(NewBlockEvent);
+        step(&mut app, Duration::from_millis(16));
     }
 
-    let new_block = next_block(&block_patterns.0);
-    let new_color = next_color(&materials.colors);
+    #[test]
+    fn corner_cells_map_to_the_board_corners() {
+        let config = GameConfig::default();
+        let half_cell = config.cell_size() / 2.0;
+        let half_board = config.board_size() / 2.0;
+        let right = X_LENGTH as i32 - 1;
+        let top = Y_LENGTH as i32 - 1;
 
-    // ブロックの初期位置
-    let initial_x = X_LENGTH / 2;
-    let initial_y = Y_LENGTH;// - 4;
+        // 四隅のマスの中心は、盤面の角から半マス内側
+        let corners = [
+            ((0, 0), Vec2::new(-half_board.x + half_cell.x, -half_board.y + half_cell.y)),
+            ((right, 0), Vec2::new(half_board.x - half_cell.x, -half_board.y + half_cell.y)),
+            ((0, top), Vec2::new(-half_board.x + half_cell.x, half_board.y - half_cell.y)),
+            ((right, top), Vec2::new(half_board.x - half_cell.x, half_board.y - half_cell.y)),
+        ];
+        for ((x, y), world) in corners {
+            assert_eq!(board_to_world(x, y, &config), world, "({}, {})", x, y);
+            assert_eq!(world_to_board(world.x, world.y, &config), (x, y));
+            // マスの端の近くでも同じマスに戻る
+            let inside = half_cell - Vec2::splat(0.5);
+            assert_eq!(world_to_board(world.x - inside.x, world.y - inside.y, &config), (x, y));
+            assert_eq!(world_to_board(world.x + inside.x, world.y + inside.y, &config), (x, y));
+        }
 
-    // ゲームオーバー判定
-    let gameover = new_block.iter().any(|(r_x, r_y)| { // <--追加
-        let pos_x = (initial_x as i32 + r_x) as usize;
-        let pos_y = (initial_y as i32 + r_y) as usize;
+        // 盤面の外側はそのまま範囲外の座標になる
+        assert_eq!(world_to_board(-half_board.x - 1.0, -half_board.y - 1.0, &config), (-1, -1));
+    }
 
-        game_board.0[pos_y][pos_x]
-    });
+    #[test]
+    fn i_piece_rotates_through_the_srs_orientations() {
+        let mut pattern = standard_block_patterns()[0].clone();
+        // 4x4 の枠 (-1..=2, -1..=2) の中で、L → 0 → R → 2 の順に回る（上が +y）
+        let states = [
+            vec![(0, -1), (0, 0), (0, 1), (0, 2)],
+            vec![(-1, 1), (0, 1), (1, 1), (2, 1)],
+            vec![(1, -1), (1, 0), (1, 1), (1, 2)],
+            vec![(-1, 0), (0, 0), (1, 0), (2, 0)],
+        ];
 
-    if gameover {
-        // ブロックを生成せずにゲームオーバーイベントを通知
-        gameover_events.send(GameOverEvent);
-        println!("Game Over");
-        return;
+        for state in states.iter().cycle().take(states.len() + 1) {
+            let mut cells = pattern.cells.clone();
+            cells.sort();
+            assert_eq!(&cells, state);
+            pattern.cells = rotate_pattern(&pattern);
+        }
     }
 
-    new_block.iter().for_each(|(r_x, r_y)| {
-        // ブロック エンティティの作成
-        commands
-        .spawn(SpriteBundle {
-            sprite: Sprite {
-                color: new_color,
-                ..Sprite::default()
-            },
-            ..SpriteBundle::default()
-        })
-        .insert(Position {
-            // ブロックの初期座標
-            // x: 0 ～ 9
-            // y: 0 ～ 17
-            x: (initial_x as i32 + r_x),
-            y: (initial_y as i32 + r_y),
-        })
-        .insert(RelativePosition {
-            rot_x: *r_x,
-            rot_y: *r_y,
-        })
-        .insert(Free);
-    });
-}
+    #[test]
+    fn lock_out_needs_every_block_above_the_visible_board() {
+        let top = Y_LENGTH as i32;
+        assert!(is_lock_out(&[(4, top), (5, top), (4, top + 1), (5, top + 1)]));
+        assert!(!is_lock_out(&[(4, top - 1), (5, top), (4, top + 1), (5, top + 1)]));
+        assert!(!is_lock_out(&[]));
+    }
 
-/**
- * System: ブロックの移動
- */
-pub(crate) fn position_transform(mut position_query: Query<(&Position, &mut Transform, &mut Sprite)>) {
-    let origin_x = UNIT_WIDTH as i32 / 2 - SCREEN_WIDTH as i32 / 2;
-    let origin_y = UNIT_HEIGHT as i32 / 2 - SCREEN_HEIGHT as i32 / 2;
+    #[test]
+    fn topout_rules_at_the_top_of_the_board() {
+        for (rule, game_over) in [(TopOutRule::LockOut, true), (TopOutRule::BlockOut, false)] {
+            let mut app = test_app(GameConfig { topout_rule: rule, ..GameConfig::default() });
+            app.add_systems(Update, block_fall);
+            // 見えている一番上の段のすぐ上で固定する
+            let top = Y_LENGTH as i32;
+            spawn_fixed(&mut app, &[(0, top - 1), (1, top - 1)]);
+            spawn_piece(&mut app, &square(), (0, top));
+            app.world.send_event(GravityTickEvent);
+            step(&mut app, Duration::from_millis(16));
 
-    position_query
-        .iter_mut()
-        .for_each(|(pos, mut transform, mut sprite)| {
-            transform.translation = Vec3::new(
-                (origin_x + pos.x as i32 * UNIT_WIDTH as i32) as f32,
-                (origin_y + pos.y as i32 * UNIT_WIDTH as i32) as f32,
-                0.0,
-            );
-            sprite.custom_size = Some(Vec2::new(UNIT_WIDTH as f32, UNIT_HEIGHT as f32))
-        });
-}
+            assert_eq!(sent_events::<GameOverEvent>(&app), game_over as usize, "{}", rule);
+            assert_eq!(sent_events::<NewBlockEvent>(&app), !game_over as usize, "{}", rule);
+        }
 
-/**
- * System: タイマーを進める
- */
-pub(crate) fn game_timer(
-    time: Res<Time>,
-    mut game_timer: ResMut<GameTimer>,
-    mut input_timer: ResMut<InputTimer>
-) {
-    game_timer.0.tick(time.delta());
-    input_timer.0.tick(time.delta());
-}
+        // 一番上の段に１つでも掛かっていれば、どちらのルールでも続ける
+        for rule in [TopOutRule::LockOut, TopOutRule::BlockOut] {
+            let mut app = test_app(GameConfig { topout_rule: rule, ..GameConfig::default() });
+            app.add_systems(Update, block_fall);
+            let top = Y_LENGTH as i32;
+            spawn_fixed(&mut app, &[(0, top - 2), (1, top - 2)]);
+            spawn_piece(&mut app, &square(), (0, top - 1));
+            app.world.send_event(GravityTickEvent);
+            step(&mut app, Duration::from_millis(16));
 
-/**
- * System: ブロックの落下
- */
-pub(crate) fn block_fall(
-    mut commands: Commands,
-    timer: ResMut<GameTimer>,
-    mut block_query: Query<(Entity, &mut Position, &Free)>,
-    mut game_board: ResMut<GameBoard>,
-    mut new_block_events: ResMut<Events<NewBlockEvent>>,
-) {
-    if !timer.0.finished() {
-        return;
+            assert_eq!(sent_events::<GameOverEvent>(&app), 0, "{}", rule);
+            assert_eq!(sent_events::<NewBlockEvent>(&app), 1, "{}", rule);
+        }
     }
 
-    // ブロックがそれ以上落下できないかを調べる
-    let cannot_fall = block_query.iter_mut().any(|(_, pos, _)| {
-        if pos.x as u32 >= X_LENGTH || pos.y as u32 >= Y_LENGTH {
-            return false;
-        }
+    #[test]
+    fn rotations_within_the_cooldown_apply_once() {
+        let mut app = test_app(GameConfig::default());
+        app.add_systems(Update, (game_timer, block_rotate).chain());
+        let t = standard_block_patterns()[6].clone();
+        spawn_piece(&mut app, &t, (4, 10));
+        let once = rotate_pattern(&t);
+        let twice = rotate_pattern(&block_pattern(once.clone(), (t.center.x2, t.center.y2)));
+        let press_up = |app: &mut App, duration: Duration| {
+            app.world.resource_mut::<Input<KeyCode>>().release(KeyCode::Up);
+            app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::Up);
+            step(app, duration);
+        };
 
-        // yが0、または一つ下にブロックがすでに存在する
-        pos.y == 0 || game_board.0[(pos.y - 1) as usize][pos.x as usize]
-    });
+        press_up(&mut app, Duration::from_millis(16));
+        assert_eq!(free_cells(&mut app), placed(&once, (4, 10)));
 
-    if cannot_fall {
-        // 落下できない
-        block_query.iter_mut().for_each(|(entity, pos, _)| {
-            commands.entity(entity).remove::<Free>();
-            commands.entity(entity).insert(Fix);
-            game_board.0[pos.y as usize][pos.x as usize] = true;
-        });
-        // 新しくブロックを生成するためのイベントを通知
-        new_block_events.send(NewBlockEvent);
-    } else {
-        // 落下
-        block_query.iter_mut().for_each(|(_, mut pos, _)| {
-            pos.y -= 1;
-        });
+        // rotate_interval_ms（100 ミリ秒）以内にもう一度押しても回らない
+        press_up(&mut app, Duration::from_millis(16));
+        assert_eq!(free_cells(&mut app), placed(&once, (4, 10)));
+
+        // 間隔を空ければまた回る
+        press_up(&mut app, Duration::from_millis(100));
+        assert_eq!(free_cells(&mut app), placed(&twice, (4, 10)));
     }
-}
 
-/**
- * System: ブロックの水平移動
- */
-pub(crate) fn block_horizontal_move(
-    key_input: Res<Input<KeyCode>>,
-    timer: ResMut<InputTimer>,
-    game_board: ResMut<GameBoard>,
-    mut free_block_query: Query<(Entity, &mut Position, &Free)>,
-) {
-    if !timer.0.finished() {
-        return;
+    #[test]
+    fn new_game_starts_from_an_empty_board_with_one_piece() {
+        let mut app = test_app(GameConfig::default());
+        app.add_systems(Update, (restart_in_play, apply_deferred, spawn_block).chain());
+        // 前のゲームの途中の状態
+        spawn_fixed(&mut app, &[(0, 0), (1, 0), (2, 0), (0, 1)]);
+        spawn_piece(&mut app, &square(), (4, 10));
+        app.world.resource_mut::<Score>().0 = 1200;
+        app.world.resource_mut::<Hold>().pattern = Some(square().cells);
+        app.world.resource_mut::<GameTimer>().0.tick(Duration::from_millis(200));
+        app.world.resource_mut::<ComboTimer>().0.tick(Duration::from_millis(500));
+        app.world.resource_mut::<SpawnDelay>().0 = Some(Timer::from_seconds(10.0, TimerMode::Once));
+        app.world.resource_mut::<PieceCounter>().0 = 7;
+        app.world.resource_mut::<PracticeCheckpoint>().0 = Some(Checkpoint {
+            board: GameBoard::new().0,
+            blocks: vec![],
+            rng: StdRng::seed_from_u64(1),
+            piece_counter: 7,
+            combo: 0,
+            score: 1200,
+            piece_bag: PieceBag::default(),
+            next_piece: NextPiece::default(),
+            hold: Hold::default(),
+        });
+
+        app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::R);
+        step(&mut app, Duration::from_millis(16));
+
+        assert!(app.world.resource::<GameBoard>().0.iter().flatten().all(|filled| !filled));
+        assert!(fixed_cells(&mut app).is_empty());
+        assert_eq!(free_cells(&mut app).len(), 4);
+        assert_eq!(free_piece_count(&mut app), 1);
+        assert_eq!(app.world.resource::<Score>().0, 0);
+        assert_eq!(app.world.resource::<Hold>().pattern, None);
+        // 途中まで進んでいたタイマーや待っていた生成は、最初からやり直す
+        assert_eq!(app.world.resource::<GameTimer>().0.elapsed(), Duration::ZERO);
+        assert_eq!(app.world.resource::<ComboTimer>().0.elapsed(), Duration::ZERO);
+        assert!(app.world.resource::<SpawnDelay>().0.is_none());
+        assert!(app.world.resource::<PracticeCheckpoint>().0.is_none());
+        // ピースの番号も最初から数え直す
+        let mut piece_query = app.world.query_filtered::<&PieceId, With<Free>>();
+        assert!(piece_query.iter(&app.world).all(|piece_id| piece_id.0 == 0));
+        assert_eq!(app.world.resource::<PieceCounter>().0, 1);
     }
 
-    if key_input.pressed(KeyCode::Left) {
-        // 左に移動できるか判定
-        let ok_move_left = free_block_query.iter_mut().all(|(_, pos, _)| {
-            if pos.y as u32 >= Y_LENGTH {
-                return pos.x > 0;
-            }
+    #[test]
+    fn new_piece_does_not_drop_on_its_spawn_frame() {
+        let mut app = test_app(GameConfig::default());
+        app.add_systems(Update, (spawn_block, game_timer, block_fall).chain());
+        // 落下のタイミングの直前に生成する
+        app.world.resource_mut::<GameTimer>().0.tick(Duration::from_millis(390));
+        set_next_piece(&mut app, &square());
+        app.world.send_event(NewBlockEvent);
+        step(&mut app, Duration::from_millis(30));
+        let spawned = free_cells(&mut app);
+        assert_eq!(spawned, vec![(5, 18), (5, 19), (6, 18), (6, 19)]);
 
-            if pos.x == 0 {
-                return false;
-            }
+        // 生成してから落下間隔１回分は留まる
+        step(&mut app, Duration::from_millis(30));
+        assert_eq!(free_cells(&mut app), spawned);
+        step(&mut app, Duration::from_millis(350));
+        assert_eq!(free_cells(&mut app), offset_cells(&spawned, 0, -1));
+    }
 
-            !game_board.0[(pos.y) as usize][pos.x as usize - 1]
-        });
+    #[test]
+    fn best_placement_prefers_the_nearest_of_equal_spots() {
+        // 原点の２マス上にある１マスだけのピース（回すと原点の左右に回り込む）
+        let game_board = GameBoard::new();
+        let center = RotationCenter { x2: 0, y2: 0 };
+        let right_edge = X_LENGTH as i32 - 1;
+        let piece = [((5, 17), (0, 2))];
 
-        if ok_move_left {
-            free_block_query.iter_mut().for_each(|(_, mut pos, _)| {
-                pos.x -= 1;
-            });
-        }
+        // 空の盤面では左右の端が同じ評価になる
+        // 向きを変えた候補とも、それぞれの原点の位置で比べて近い方（右端）を選ぶ
+        let placement = best_placement(&game_board, &piece, &center);
+        assert_eq!(placement, Some(vec![(right_edge, 0)]));
     }
 
-    if key_input.pressed(KeyCode::Right) {
-        // 右に移動できるか判定
-        let ok_move_right = free_block_query.iter_mut().all(|(_, pos, _)| {
-            if pos.y as u32 >= Y_LENGTH {
-                return pos.x as u32 <= X_LENGTH;
-            }
+    #[test]
+    fn malformed_puzzle_file_is_rejected() {
+        let path = std::env::temp_dir().join(format!("study_tetris_puzzle_{}.txt", std::process::id()));
+        let config = GameConfig { puzzle_file: Some(path.to_str().unwrap().to_string()), ..GameConfig::default() };
 
-            if pos.x as u32 == X_LENGTH - 1 {
-                return false;
-            }
+        // 行が足りない
+        std::fs::write(&path, "#.........\n").unwrap();
+        let short = load_puzzle(&config);
+        // 知らない文字がある
+        let mut rows = vec![".".repeat(X_LENGTH as usize); Y_LENGTH as usize];
+        rows[0] = format!("?{}", ".".repeat(X_LENGTH as usize - 1));
+        std::fs::write(&path, rows.join("\n")).unwrap();
+        let invalid = load_puzzle(&config);
+        // 正しいファイル
+        rows[0] = format!("#{}", ".".repeat(X_LENGTH as usize - 1));
+        std::fs::write(&path, rows.join("\n")).unwrap();
+        let valid = load_puzzle(&config);
+        std::fs::remove_file(&path).unwrap();
 
-            !game_board.0[(pos.y) as usize][pos.x as usize + 1]
-        });
+        let message = short.unwrap_err();
+        assert!(message.starts_with("puzzle: ") && message.contains("expected"), "{}", message);
+        let message = invalid.unwrap_err();
+        assert!(message.starts_with("puzzle: ") && message.contains("invalid cell '?'"), "{}", message);
+        // 上の行から読むので、ファイルの１行目が盤面の一番上になる
+        let layout = valid.unwrap().unwrap();
+        assert_eq!(layout[Y_LENGTH as usize - 1][0], Some(PUZZLE_BLOCK_COLOR));
+        assert_eq!(layout.iter().flatten().filter(|cell| cell.is_some()).count(), 1);
+        assert_eq!(load_puzzle(&GameConfig::default()), Ok(None));
+    }
 
-        if ok_move_right {
-            free_block_query.iter_mut().for_each(|(_, mut pos, _)| {
-                pos.x += 1;
-            });
-        }
+    #[test]
+    fn malformed_config_falls_back_to_defaults() {
+        let path = std::env::temp_dir().join(format!("study_tetris_config_{}.txt", std::process::id()));
+        let text = "\
+            # 壊れた設定ファイル\n\
+            gravity_ms = fast\n\
+            cell_width = 0\n\
+            block_opacity = 2.5\n\
+            input_interval_ms = 0\n\
+            soft_drop_style = sideways\n\
+            no separator here\n\
+            unknown_key = 1\n\
+            combo_time_ms = 5000\n\
+            rotate_interval_ms = 60000\n\
+            ghost_fade_ms = 99999999\n\
+            spawn_jitter_ms = 99999999\n\
+            max_fps = 1\n\
+            ui_scale = 9.0\n";
+        std::fs::write(&path, text).unwrap();
+        let config = GameConfig::load(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        let default = GameConfig::default();
+        assert_eq!(config.gravity_ms, default.gravity_ms);
+        assert_eq!(config.cell_width, default.cell_width);
+        assert_eq!(config.block_opacity, default.block_opacity);
+        assert_eq!(config.input_interval_ms, default.input_interval_ms);
+        assert_eq!(config.soft_drop_style, default.soft_drop_style);
+        assert_eq!(config.rotate_interval_ms, default.rotate_interval_ms);
+        assert_eq!(config.ghost_fade_ms, default.ghost_fade_ms);
+        assert_eq!(config.spawn_jitter_ms, default.spawn_jitter_ms);
+        assert_eq!(config.max_fps, default.max_fps);
+        assert_eq!(config.ui_scale, default.ui_scale);
+        // 正しい行はそのまま読み込む
+        assert_eq!(config.combo_time_ms, 5000);
+        // ゲームを動かせる値になっている
+        assert!(config.gravity_interval() > Duration::ZERO);
+        assert!(config.cell_size().min_element() > 0.0);
     }
-}
 
-/**
- * System: ブロックの下移動
- */
-pub(crate) fn block_vertical_move(
-    key_input: Res<Input<KeyCode>>,
-    mut game_board: ResMut<GameBoard>,
-    mut free_block_query: Query<(Entity, &mut Position, &Free)>,
-) {
-    if !key_input.just_pressed(KeyCode::Down) {
-        return;
+    #[test]
+    fn missing_config_file_uses_defaults() {
+        let config = GameConfig::load("no/such/config.txt");
+        assert_eq!(config.gravity_ms, GameConfig::default().gravity_ms);
+        assert_eq!(config.cell_width, GameConfig::default().cell_width);
     }
 
-    let mut down_height = 0;
-    let mut collide = false;
+    #[test]
+    fn non_square_cells_map_to_the_expected_pixel() {
+        let config = GameConfig { cell_width: 30, cell_height: 50, ..GameConfig::default() };
+        // 盤面は 300 x 900 ピクセルで、中心がワールド座標の原点
+        assert_eq!(board_to_world(0, 0, &config), Vec2::new(-135.0, -425.0));
+        assert_eq!(board_to_world(2, 3, &config), Vec2::new(-75.0, -275.0));
+        assert_eq!(world_to_board(-75.0, -275.0, &config), (2, 3));
 
-    // ブロックが衝突する位置を調べる
-    while !collide {
-        down_height += 1;
-        free_block_query.iter_mut().for_each(|(_, pos, _)| {
-            if pos.y < down_height {
-                collide = true;
-                return;
-            }
+        let mut app = test_app(config);
+        app.add_systems(Update, position_transform);
+        let block = app.world.spawn((Position { x: 2, y: 3 }, SpriteBundle::default())).id();
+        step(&mut app, Duration::from_millis(16));
+
+        let block = app.world.entity(block);
+        assert_eq!(block.get::<Transform>().unwrap().translation, Vec3::new(-75.0, -275.0, BLOCK_Z));
+        assert_eq!(block.get::<Sprite>().unwrap().custom_size, Some(Vec2::new(30.0, 50.0)));
+    }
+
+    #[test]
+    fn clearing_two_separate_rows_drops_each_block_by_the_rows_below_it() {
+        let mut app = test_app(GameConfig::default());
+        app.add_systems(Update, delete_line);
+        // y = 3 と y = 7 が揃っていて、間と上下に消えないブロックがある
+        for y in [3, 7] {
+            let row: Vec<(i32, i32)> = (0..X_LENGTH as i32).map(|x| (x, y)).collect();
+            spawn_fixed(&mut app, &row);
+        }
+        spawn_fixed(&mut app, &[(0, 2), (1, 4), (3, 5), (3, 6), (2, 8)]);
+
+        app.world.send_event(GravityTickEvent);
+        step(&mut app, Duration::from_millis(16));
+
+        // 消えた行より下はそのまま、間は１段、上は２段下がる
+        let expected = vec![(0, 2), (1, 3), (2, 6), (3, 4), (3, 5)];
+        assert_eq!(fixed_cells(&mut app), expected);
+        let game_board = app.world.resource::<GameBoard>();
+        let mut filled: Vec<(i32, i32)> = (0..game_board.height())
+            .flat_map(|y| (0..X_LENGTH as i32).map(move |x| (x, y)))
+            .filter(|(x, y)| game_board.get(*x, *y) == Some(true))
+            .collect();
+        filled.sort();
+        assert_eq!(filled, expected);
+        assert_eq!(app.world.resource::<Score>().0, line_clear_score(2));
+    }
 
-            if game_board.0[(pos.y - down_height) as usize][pos.x as usize] {
-                collide = true;
+    #[test]
+    fn one_new_block_event_spawns_one_piece() {
+        for events in [1, 2] {
+            let mut app = test_app(GameConfig::default());
+            app.add_systems(Update, spawn_block);
+            // 同じフレームに２つ届いても１つだけ
+            for _ in 0..events {
+                app.world.send_event(NewBlockEvent);
             }
-        });
+            step(&mut app, Duration::from_millis(16));
+            assert_eq!(free_piece_count(&mut app), 1);
+            assert_eq!(free_cells(&mut app).len(), 4);
+
+            // 次のフレームに持ち越さない
+            step(&mut app, Duration::from_millis(16));
+            assert_eq!(free_piece_count(&mut app), 1);
+            assert_eq!(free_cells(&mut app).len(), 4);
+        }
     }
 
-    // ブロックが衝突しないギリギリの位置まで移動
-    down_height -= 1;
-    free_block_query.iter_mut().for_each(|(_, mut pos, _)| {
-        game_board.0[pos.y as usize][pos.x as usize] = false;
-        pos.y -= down_height;
-        game_board.0[pos.y as usize][pos.x as usize] = true;
-    });
-}
+    #[test]
+    fn brief_r_tap_does_not_restart_when_confirming() {
+        let mut app = test_app(GameConfig { confirm_restart: true, ..GameConfig::default() });
+        app.add_systems(Update, restart_in_play);
+        spawn_fixed(&mut app, &[(0, 0)]);
 
-/**
- * System: ブロックの回転移動
- */
-pub(crate) fn block_rotate(
-    key_input: Res<Input<KeyCode>>,
-    game_board: ResMut<GameBoard>,
-    mut free_block_query: Query<(Entity, &mut Position, &mut RelativePosition, &Free)>,
-) {
-    if !key_input.just_pressed(KeyCode::Up) {
-        return;
+        // RESTART_HOLD_MS より短く押して離す
+        app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::R);
+        step(&mut app, Duration::from_millis(200));
+        app.world.resource_mut::<Input<KeyCode>>().release(KeyCode::R);
+        step(&mut app, Duration::from_millis(16));
+        assert_eq!(fixed_cells(&mut app), vec![(0, 0)]);
+
+        // 離すと数え直すので、合わせて長くなっても続けて押さなければやり直さない
+        app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::R);
+        step(&mut app, Duration::from_millis(400));
+        assert_eq!(fixed_cells(&mut app), vec![(0, 0)]);
+
+        // 押し続ければやり直す
+        step(&mut app, Duration::from_millis(200));
+        assert!(fixed_cells(&mut app).is_empty());
     }
 
-    // 回転行列を使って新しい絶対座標と相対座標を計算
-    fn calc_rotated_pos(pos: &Position, r_pos: &RelativePosition) -> ((i32, i32), (i32, i32)) {
-        // cos,-sin,sin,cos (-90)
-        let rot_matrix = vec![vec![0, 1], vec![-1, 0]];
+    #[test]
+    fn holding_up_at_spawn_spawns_rotated() {
+        let t = standard_block_patterns()[6].clone();
+        let spawn = (PlayfieldWalls::default().spawn_x(), Y_LENGTH as i32);
+        let cases = [
+            (true, true, rotate_pattern(&t)),
+            (true, false, t.cells.clone()),
+            (false, true, t.cells.clone()),
+        ];
 
-        let origin_pos_x = pos.x - r_pos.rot_x;
-        let origin_pos_y = pos.y - r_pos.rot_y;
+        for (initial_rotation, hold_up, expected) in cases {
+            let mut app = test_app(GameConfig { initial_rotation, ..GameConfig::default() });
+            app.add_systems(Update, spawn_block);
+            if hold_up {
+                app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::Up);
+            }
+            set_next_piece(&mut app, &t);
+            app.world.send_event(NewBlockEvent);
+            step(&mut app, Duration::from_millis(16));
 
-        let new_r_pos_x = rot_matrix[0][0] * r_pos.rot_x + rot_matrix[0][1] * r_pos.rot_y;
-        let new_r_pos_y = rot_matrix[1][0] * r_pos.rot_x + rot_matrix[1][1] * r_pos.rot_y;
-        let new_pos_x = origin_pos_x + new_r_pos_x;
-        let new_pos_y = origin_pos_y + new_r_pos_y;
+            assert_eq!(free_cells(&mut app), placed(&expected, spawn), "{} {}", initial_rotation, hold_up);
+        }
+    }
 
-        ((new_pos_x, new_pos_y), (new_r_pos_x, new_r_pos_y))
+    #[test]
+    fn holding_hard_drop_across_a_spawn_does_not_drop_the_next_piece() {
+        let mut app = test_app(GameConfig { soft_drop_style: SoftDropStyle::Instant, ..GameConfig::default() });
+        app.add_systems(Update, (spawn_block, game_timer, block_vertical_move).chain());
+        set_next_piece(&mut app, &square());
+        app.world.send_event(NewBlockEvent);
+        step(&mut app, Duration::from_millis(16));
+
+        // 一気に落として固定する
+        app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::Down);
+        step(&mut app, Duration::from_millis(16));
+        assert_eq!(fixed_cells(&mut app), vec![(5, 0), (5, 1), (6, 0), (6, 1)]);
+
+        // ↓キーを押したままでも、次のピースは生成位置に留まる
+        set_next_piece(&mut app, &square());
+        step(&mut app, Duration::from_millis(16));
+        let spawned = free_cells(&mut app);
+        assert_eq!(spawned, vec![(5, 18), (5, 19), (6, 18), (6, 19)]);
+        for _ in 0..3 {
+            step(&mut app, Duration::from_millis(100));
+        }
+        assert_eq!(free_cells(&mut app), spawned);
+        assert_eq!(fixed_cells(&mut app).len(), 4);
     }
 
-    // 回転操作可能かどうか判定
-    let rotable = free_block_query.iter_mut().all(|(_, pos, r_pos, _)| {
-        let ((new_pos_x, new_pos_y), _) = calc_rotated_pos(&pos, &r_pos);
+    #[test]
+    fn hard_drop_locks_on_the_same_frame_only_when_enabled() {
+        for hard_drop_locks in [true, false] {
+            let config = GameConfig { soft_drop_style: SoftDropStyle::Instant, hard_drop_locks, ..GameConfig::default() };
+            let mut app = test_app(config);
+            app.add_systems(Update, (game_timer, block_vertical_move, apply_deferred, block_fall).chain());
+            spawn_piece(&mut app, &square(), (4, 10));
+            let bottom = placed(&square().cells, (4, 0));
 
-        let valid_index_x = new_pos_x >= 0 && new_pos_x < X_LENGTH as i32;
-        let valid_index_y = new_pos_y >= 0 && new_pos_y < Y_LENGTH as i32;
+            app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::Down);
+            step(&mut app, Duration::from_millis(16));
 
-        if !valid_index_x || !valid_index_y {
-            return false;
+            if hard_drop_locks {
+                // 落としたフレームに固定する
+                assert!(free_cells(&mut app).is_empty());
+                assert_eq!(fixed_cells(&mut app), bottom);
+                assert_eq!(sent_events::<NewBlockEvent>(&app), 1);
+                continue;
+            }
+
+            // 一番下で操作中のまま留まり、次の落下タイミングで固定する
+            assert_eq!(free_cells(&mut app), bottom);
+            assert!(fixed_cells(&mut app).is_empty());
+            step(&mut app, Duration::from_millis(300));
+            assert_eq!(free_cells(&mut app), bottom);
+            assert_eq!(sent_events::<NewBlockEvent>(&app), 0);
+            step(&mut app, Duration::from_millis(150));
+            assert!(free_cells(&mut app).is_empty());
+            assert_eq!(fixed_cells(&mut app), bottom);
+            assert_eq!(sent_events::<NewBlockEvent>(&app), 1);
         }
+    }
 
-        !game_board.0[new_pos_y as usize][new_pos_x as usize]
-    });
+    #[test]
+    fn first_hold_spawns_the_next_piece() {
+        let mut app = test_app(GameConfig::default());
+        app.add_systems(Update, (hold_piece, apply_deferred, spawn_block).chain());
+        set_next_piece(&mut app, &square());
+        app.world.send_event(NewBlockEvent);
+        step(&mut app, Duration::from_millis(16));
+        let queued = next_piece_cells(&app);
+        let spawn = (PlayfieldWalls::default().spawn_x(), Y_LENGTH as i32);
 
-    if !rotable {
-        return;
+        // ホールドが空の時は、次のピースとして見せていたものが出てくる
+        press_shift(&mut app);
+        step(&mut app, Duration::from_millis(16));
+
+        let hold = app.world.resource::<Hold>();
+        assert_eq!(hold.pattern, Some(square().cells));
+        assert!(hold.used_this_turn);
+        assert_eq!(free_cells(&mut app), placed(&queued, spawn));
+        assert_eq!(free_piece_count(&mut app), 1);
     }
 
-    // 相対座標と絶対座標を更新
-    free_block_query
-        .iter_mut()
-        .for_each(|(_, mut pos, mut r_pos, _)| {
-            let ((new_pos_x, new_pos_y), (new_r_pos_x, new_r_pos_y)) =
-                calc_rotated_pos(&pos, &r_pos);
-            r_pos.rot_x = new_r_pos_x;
-            r_pos.rot_y = new_r_pos_y;
+    #[test]
+    fn hold_box_shows_the_piece_as_it_spawns_on_a_swap() {
+        let mut app = test_app(GameConfig::default());
+        app.add_systems(Update, (block_rotate, hold_piece, apply_deferred, spawn_block, render_hold_piece).chain());
+        let t = standard_block_patterns()[6].clone();
+        set_next_piece(&mut app, &t);
+        app.world.send_event(NewBlockEvent);
+        step(&mut app, Duration::from_millis(16));
 
-            pos.x = new_pos_x;
-            pos.y = new_pos_y;
+        // 回した向きのままホールドしても、ホールドの表示は出てくる時の向き
+        app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::Up);
+        step(&mut app, Duration::from_millis(16));
+        assert_ne!(normalized(&free_cells(&mut app)), normalized(&t.cells));
+        app.world.resource_mut::<Input<KeyCode>>().release(KeyCode::Up);
+        press_shift(&mut app);
+        step(&mut app, Duration::from_millis(16));
+        let center = app.world.resource::<GameConfig>().hold_preview_center();
+        let hold_box = preview_shape::<HoldPreviewBlock>(&mut app, center);
+
+        // 次のピースを固定したことにして、もう一度ホールドで入れ替える
+        let free: Vec<Entity> = app.world.query_filtered::<Entity, With<Free>>().iter(&app.world).collect();
+        free.into_iter().for_each(|entity| {
+            app.world.despawn(entity);
         });
-}
+        app.world.send_event(NewBlockEvent);
+        step(&mut app, Duration::from_millis(16));
+        press_shift(&mut app);
+        step(&mut app, Duration::from_millis(16));
 
-/**
- * System: ブロックの削除
- */
-pub(crate) fn delete_line(
-    mut commands: Commands,
-    timer: ResMut<GameTimer>,
-    mut game_board: ResMut<GameBoard>,
-    mut fixed_block_query: Query<(Entity, &mut Position, &Fix)>,
-) {
-    if !timer.0.finished() {
-        return;
+        assert_eq!(hold_box, normalized(&t.cells));
+        assert_eq!(normalized(&free_cells(&mut app)), hold_box);
     }
 
-    // 消去対象のブロック行をHashSetに入れていく
-    let mut delete_line_set = std::collections::HashSet::new();
-    for y in 0..Y_LENGTH {
-        let mut delete_current_line = true;
-        for x in 0..X_LENGTH {
-            if !game_board.0[y as usize][x as usize] {
-                delete_current_line = false;
-                break;
-            }
-        }
+    #[test]
+    fn can_hold_once_per_piece() {
+        let mut hold = Hold::default();
+        assert!(can_hold(&hold));
+        hold.swap_requested = true;
+        assert!(!can_hold(&hold));
+        hold.swap_requested = false;
+        hold.used_this_turn = true;
+        assert!(!can_hold(&hold));
+    }
 
-        if delete_current_line {
-            delete_line_set.insert(y);
+    #[test]
+    fn repeated_hold_presses_do_not_cycle_pieces() {
+        let mut app = test_app(GameConfig::default());
+        app.add_systems(Update, (hold_piece, apply_deferred, spawn_block, render_hold_piece).chain());
+        set_next_piece(&mut app, &square());
+        app.world.send_event(NewBlockEvent);
+        step(&mut app, Duration::from_millis(16));
+
+        press_shift(&mut app);
+        step(&mut app, Duration::from_millis(16));
+        let held = app.world.resource::<Hold>().pattern.clone();
+        let swapped_in = free_cells(&mut app);
+        let queued = next_piece_cells(&app);
+        // ホールドした時だけ表示を拡大して見せる
+        let animated = app.world.query_filtered::<(), (With<HoldPreviewBlock>, With<SpawnAnimation>)>().iter(&app.world).count();
+        assert_eq!(animated, 4);
+
+        // 次のピースが出るまでは、何度押しても入れ替わらない
+        for _ in 0..5 {
+            press_shift(&mut app);
+            step(&mut app, Duration::from_millis(16));
         }
+        assert_eq!(app.world.resource::<Hold>().pattern, held);
+        assert_eq!(free_cells(&mut app), swapped_in);
+        assert_eq!(next_piece_cells(&app), queued);
     }
 
-    // 消去対象ブロック行に含まれるブロックをゲーム盤面から削除する
-    fixed_block_query.iter_mut().for_each(|(_, pos, _)| {
-        if delete_line_set.get(&(pos.y as u32)).is_some() {
-            game_board.0[pos.y as usize][pos.x as usize] = false;
+    #[test]
+    fn ghost_clear_rows_overlays_the_landed_piece() {
+        let mut game_board = GameBoard::new();
+        // y = 0, 1 は x = 4, 5 だけ、y = 2 は x = 4, 5, 6 が空いている
+        let hole = |x: i32, y: i32| (4..=5).contains(&x) || (y == 2 && x == 6);
+        for y in 0..3 {
+            (0..X_LENGTH as i32).filter(|x| !hole(*x, y)).for_each(|x| {
+                game_board.set(x, y, true);
+            });
         }
-    });
 
-    // 各Y座標について、ブロック消去適用後の新しいY座標を調べる
-    let mut new_y = vec![0i32; Y_LENGTH as usize];
-    for y in 0..Y_LENGTH {
-        let mut down = 0;
-        delete_line_set.iter().for_each(|line| {
-            if y > *line {
-                down += 1;
-            }
-        });
-        new_y[y as usize] = y as i32 - down;
+        assert_eq!(ghost_clear_rows(&game_board, &[(4, 0), (5, 0), (4, 1), (5, 1)]), vec![0, 1]);
+        assert_eq!(ghost_clear_rows(&game_board, &[(4, 1), (5, 1), (4, 2), (5, 2)]), vec![1]);
+        assert!(ghost_clear_rows(&game_board, &[(4, 3), (5, 3), (4, 4), (5, 4)]).is_empty());
     }
 
+    #[test]
+    fn ghost_highlights_clearing_rows_only_when_enabled() {
+        for (ghost_clear_rows, highlighted) in [(false, 0), (true, 2)] {
+            let mut app = test_app(GameConfig { ghost_clear_rows, ..GameConfig::default() });
+            app.add_systems(Update, render_ghost);
+            let row: Vec<(i32, i32)> = (0..X_LENGTH as i32).filter(|x| !(4..=5).contains(x)).flat_map(|x| [(x, 0), (x, 1)]).collect();
+            spawn_fixed(&mut app, &row);
+            spawn_piece(&mut app, &square(), (4, 10));
+            step(&mut app, Duration::from_millis(16));
 
-    fixed_block_query.iter_mut().for_each(|(entity, mut pos, _)| {
-        if delete_line_set.get(&(pos.y as u32)).is_some() {
-            // 消去の対象のブロックをゲームから取り除く
-            commands.entity(entity).despawn();
-        } else {
-            // ブロック消去適用後の新しいY座標を適用
-            game_board.0[pos.y as usize][pos.x as usize] = false;
-            pos.y = new_y[pos.y as usize];
-            game_board.0[pos.y as usize][pos.x as usize] = true;
+            let ghosts = app.world.query_filtered::<(), With<GhostBlock>>().iter(&app.world).count();
+            assert_eq!(ghosts, 4 + highlighted);
         }
-    });
-}
+    }
 
-/**
- * System: ゲームオーバー通知を受けた時の処理
- */
-pub(crate) fn gameover(
-    mut commands: Commands,
-    gameover_events: Res<Events<GameOverEvent>>,
-    mut game_board: ResMut<GameBoard>,
-    mut all_block_query: Query<(Entity, &mut Position)>,
-    mut new_block_events: ResMut<Events<NewBlockEvent>>,
-) {
-    let mut gameover_events_reader = gameover_events.get_reader();
+    #[test]
+    fn ghost_fades_while_idle_and_returns_on_input() {
+        let mut app = test_app(GameConfig { ghost_fade_ms: 500, ..GameConfig::default() });
+        app.add_systems(Update, (game_timer, block_horizontal_move, render_ghost).chain());
+        spawn_piece(&mut app, &square(), (4, 10));
+        let ghost_alpha = |app: &mut App| {
+            let alphas: Vec<f32> = app.world.query::<(&GhostBlock, &Sprite)>().iter(&app.world).map(|(_, sprite)| sprite.color.a()).collect();
+            assert_eq!(alphas.len(), 4);
+            alphas[0]
+        };
 
-    if gameover_events_reader
-        .read(&gameover_events)
-        .next()
-        .is_none()
-    {
-        return;
+        step(&mut app, Duration::from_millis(16));
+        assert!(ghost_alpha(&mut app) > 0.0);
+
+        // 操作しないまま ghost_fade_ms が過ぎると見えなくなる
+        for _ in 0..6 {
+            step(&mut app, Duration::from_millis(100));
+        }
+        assert_eq!(ghost_alpha(&mut app), 0.0);
+
+        // 動かすとまた見える
+        app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::Left);
+        step(&mut app, Duration::from_millis(100));
+        assert!((ghost_alpha(&mut app) - 0.3).abs() < 1e-6);
     }
 
-    game_board.0 = vec![vec![false; 25]; 25];
-    all_block_query.iter_mut().for_each(|(entity, _)| {
-        commands.entity(entity).despawn();
-    });
+    #[test]
+    fn undo_returns_the_piece_to_the_current_spawn_column() {
+        let mut app = test_app(GameConfig {
+            mode: GameMode::Practice,
+            soft_drop_style: SoftDropStyle::Instant,
+            ..GameConfig::default()
+        });
+        app.add_systems(Update, (spawn_block, block_vertical_move, apply_deferred, undo_lock).chain());
+        // 盤面が狭まって生成する列がずれている
+        let walls = PlayfieldWalls { left: 2, right: 0 };
+        let spawn = (walls.spawn_x(), Y_LENGTH as i32);
+        assert_ne!(walls.spawn_x(), PlayfieldWalls::default().spawn_x());
+        app.insert_resource(walls);
 
-    new_block_events.send(NewBlockEvent);
-}
\ No newline at end of file
+        set_next_piece(&mut app, &square());
+        app.world.send_event(NewBlockEvent);
+        step(&mut app, Duration::from_millis(16));
+        app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::Down);
+        step(&mut app, Duration::from_millis(16));
+        let (displaced, _) = app.world.resource::<NextPiece>().0.clone().unwrap();
+        app.world.resource_mut::<Input<KeyCode>>().release(KeyCode::Down);
+        step(&mut app, Duration::from_millis(16));
+        let (upcoming, _) = app.world.resource::<NextPiece>().0.clone().unwrap();
+        assert_eq!(free_piece_count(&mut app), 1);
+
+        let mut key_input = app.world.resource_mut::<Input<KeyCode>>();
+        key_input.press(KeyCode::ControlLeft);
+        key_input.press(KeyCode::Z);
+        step(&mut app, Duration::from_millis(16));
+
+        assert_eq!(free_cells(&mut app), placed(&square().cells, spawn));
+        assert!(fixed_cells(&mut app).is_empty());
+        // 操作中だったピースは次のピースに、次のピースだったものは袋の先頭に戻る
+        let (next, _) = app.world.resource::<NextPiece>().0.clone().unwrap();
+        assert_eq!(next.cells, displaced.cells);
+        let upcoming_index = standard_block_patterns().iter().position(|p| p.cells == upcoming.cells);
+        assert_eq!(app.world.resource::<PieceBag>().queue.front().copied(), upcoming_index);
+    }
+
+    #[test]
+    fn checkpoint_from_a_previous_game_is_not_restored() {
+        let mut app = test_app(GameConfig { mode: GameMode::Practice, ..GameConfig::default() });
+        app.add_systems(Update, (save_checkpoint, restore_checkpoint, restart_in_play).chain());
+        spawn_fixed(&mut app, &[(0, 0), (1, 0)]);
+        app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::F5);
+        step(&mut app, Duration::from_millis(16));
+        assert!(app.world.resource::<PracticeCheckpoint>().0.is_some());
+
+        // やり直した後に F9 を押しても、前のゲームの盤面には戻らない
+        app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::R);
+        step(&mut app, Duration::from_millis(16));
+        app.world.resource_mut::<Input<KeyCode>>().release(KeyCode::R);
+        app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::F9);
+        step(&mut app, Duration::from_millis(16));
+
+        assert!(app.world.resource::<PracticeCheckpoint>().0.is_none());
+        assert_eq!(app.world.resource::<PieceCounter>().0, 0);
+        assert!(fixed_cells(&mut app).is_empty());
+        assert!(app.world.resource::<GameBoard>().0.iter().flatten().all(|filled| !filled));
+    }
+
+    #[test]
+    fn score_text_is_left_unchanged_while_the_score_stays_the_same() {
+        #[derive(Resource, Default)]
+        struct ChangedTexts(usize);
+
+        let mut app = test_app(GameConfig::default());
+        app.init_resource::<ChangedTexts>();
+        app.add_systems(
+            Update,
+            (
+                update_score_text,
+                |text_query: Query<(), (With<ScoreText>, Changed<Text>)>, mut changed: ResMut<ChangedTexts>| {
+                    changed.0 += text_query.iter().count();
+                },
+            )
+                .chain(),
+        );
+        let text = Text::from_sections([TextSection::new("SCORE: ", TextStyle::default()), TextSection::new("0", TextStyle::default())]);
+        let entity = app.world.spawn((text, ScoreText)).id();
+        step(&mut app, Duration::from_millis(16));
+        app.world.resource_mut::<ChangedTexts>().0 = 0;
+
+        // 得点が変わらない間は書き換えない
+        for _ in 0..5 {
+            step(&mut app, Duration::from_millis(16));
+        }
+        assert_eq!(app.world.resource::<ChangedTexts>().0, 0);
+
+        // 得点が変わったフレームだけ書き換える
+        app.world.resource_mut::<Score>().0 = 300;
+        step(&mut app, Duration::from_millis(16));
+        step(&mut app, Duration::from_millis(16));
+        assert_eq!(app.world.resource::<ChangedTexts>().0, 1);
+        assert_eq!(app.world.get::<Text>(entity).unwrap().sections[1].value, "300");
+    }
+}